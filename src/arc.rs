@@ -0,0 +1,253 @@
+//! A streaming reader for the legacy ARC format, which predates WARC and
+//! holds older crawls. Each ARC record is a one-line header ending in the
+//! length of the block that follows, so, as with WARC, blocks are read by
+//! length rather than by scanning for boundaries.
+//!
+//! Records are returned as WARC records with equivalent headers, so the
+//! rest of the pipeline treats both formats alike.
+
+use crate::warc::{Failure, ReadRecord, Record, RecordStream};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// The longest block a header line may claim. ARC writers cap captures
+/// well below this, so a longer length is a corrupt or stray line, and
+/// reading it as one would swallow the rest of the archive.
+const MAX_RECORD_LENGTH: u64 = 256 * 1024 * 1024;
+
+/// The fields of an ARC record header line. Version 1 lines hold the url,
+/// IP address, date, content type, and length; version 2 lines add more
+/// fields before the length, which is always last.
+struct Header {
+    url: String,
+    ip_address: String,
+    date: String,
+    content_type: String,
+    length: u64,
+}
+
+impl Header {
+    fn parse(line: &str) -> Option<Header> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 5 || !fields[0].contains(':') {
+            return None;
+        }
+        // Dates are digits, though not always to the second.
+        let date = fields[2];
+        if date.len() < 8 || !date.bytes().all(|x| x.is_ascii_digit()) {
+            return None;
+        }
+        let length: u64 = fields[fields.len() - 1].parse().ok()?;
+        if length > MAX_RECORD_LENGTH {
+            return None;
+        }
+        Some(Header {
+            url: String::from(fields[0]),
+            ip_address: String::from(fields[1]),
+            date: String::from(date),
+            content_type: String::from(fields[3]),
+            length,
+        })
+    }
+}
+
+/// Reads ARC records one at a time from an uncompressed stream, as WARC
+/// records: the file description becomes a `warcinfo` record and every
+/// capture a `response`. After a malformed header, reading resumes at the
+/// next valid header line that follows a blank line, so the lines of the
+/// block that the header described aren't mistaken for headers.
+pub struct ArcReader<R: BufRead> {
+    stream: RecordStream<R>,
+    /// Whether the last header was malformed, so that the stream is no
+    /// longer at a record boundary.
+    resyncing: bool,
+}
+
+impl<R: BufRead> ArcReader<R> {
+    pub fn new(reader: R) -> ArcReader<R> {
+        ArcReader {
+            stream: RecordStream::new(reader),
+            resyncing: false,
+        }
+    }
+}
+
+impl<R: BufRead> ReadRecord for ArcReader<R> {
+    fn read_record(&mut self) -> Result<Option<Record>, Failure> {
+        // Records are separated by blank lines. A record should start at
+        // the first line that isn't blank, unless the last header was
+        // malformed, in which case anything up to a blank line is skipped.
+        let mut skipped = 0;
+        let mut at_boundary = !self.resyncing;
+        let (offset, header) = loop {
+            let start = self.stream.offset();
+            let line = self.stream.read_line()?;
+            if line.is_empty() {
+                if skipped > 0 {
+                    return Err(Failure::Fatal(format!(
+                        "archive ended after {} bytes of unparseable data",
+                        skipped
+                    )));
+                }
+                return Ok(None);
+            }
+            let text = String::from_utf8_lossy(&line).trim().to_string();
+            if text.is_empty() {
+                at_boundary = true;
+                continue;
+            }
+            if at_boundary {
+                if let Some(header) = Header::parse(text.as_str()) {
+                    if skipped > 0 {
+                        warn!("skipped {} bytes before record at {}", skipped, start);
+                    }
+                    self.resyncing = false;
+                    break (start, header);
+                }
+            }
+            if !self.resyncing {
+                self.resyncing = true;
+                return Err(Failure::Malformed(format!(
+                    "malformed header line in record at {}",
+                    start
+                )));
+            }
+            skipped += line.len();
+            at_boundary = false;
+        };
+
+        let content = self.stream.read_block(header.length, offset)?;
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let record_type = if header.url.starts_with("filedesc:") {
+            "warcinfo"
+        } else {
+            "response"
+        };
+        // Captures store the whole HTTP response, as WARC does.
+        let content_type = if record_type == "response" && content.starts_with(b"HTTP/") {
+            String::from("application/http; msgtype=response")
+        } else {
+            header.content_type
+        };
+        headers.insert(String::from("WARC-Type"), String::from(record_type));
+        headers.insert(String::from("WARC-Target-URI"), header.url);
+        headers.insert(String::from("WARC-IP-Address"), header.ip_address);
+        headers.insert(String::from("WARC-Date"), warc_date(header.date.as_str()));
+        headers.insert(String::from("Content-Type"), content_type);
+        headers.insert(String::from("Content-Length"), header.length.to_string());
+        Ok(Some(Record {
+            headers,
+            content,
+            offset,
+        }))
+    }
+}
+
+/// Converts an ARC date (`YYYYMMDDhhmmss`) to WARC's ISO 8601 form,
+/// leaving anything unexpected as-is.
+fn warc_date(date: &str) -> String {
+    if date.len() != 14 || !date.bytes().all(|x| x.is_ascii_digit()) {
+        return String::from(date);
+    }
+    format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &date[0..4],
+        &date[4..6],
+        &date[6..8],
+        &date[8..10],
+        &date[10..12],
+        &date[12..14]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warc::Records;
+
+    fn record(url: &str, content: &[u8]) -> Vec<u8> {
+        let mut data = format!("{} 93.184.216.34 20080430204825 text/html {}\n", url, content.len()).into_bytes();
+        data.extend_from_slice(content);
+        data.extend_from_slice(b"\n");
+        data
+    }
+
+    fn read(data: &[u8]) -> Vec<Result<Record, Failure>> {
+        Records::new(ArcReader::new(data)).collect()
+    }
+
+    #[test]
+    fn parses_version_1_and_2_headers() {
+        let header = Header::parse("http://example.com/ 93.184.216.34 20080430204825 text/html 42").unwrap();
+        assert_eq!(header.url, "http://example.com/");
+        assert_eq!(header.ip_address, "93.184.216.34");
+        assert_eq!(header.date, "20080430204825");
+        assert_eq!(header.content_type, "text/html");
+        assert_eq!(header.length, 42);
+
+        let line = "http://example.com/ 93.184.216.34 20080430204825 text/html 200 \
+            ABCDEFGHIJKLMNOPQRSTUVWXYZ234567 - 1234 example.arc.gz 1024";
+        let header = Header::parse(line).unwrap();
+        assert_eq!(header.content_type, "text/html");
+        assert_eq!(header.length, 1024);
+
+        assert!(Header::parse("http://example.com/ 93.184.216.34 20080430204825 42").is_none());
+        assert!(Header::parse("example.com 93.184.216.34 20080430204825 text/html 42").is_none());
+        assert!(Header::parse("http://example.com/ 93.184.216.34 yesterday text/html 42").is_none());
+        assert!(Header::parse("a:b x 20080430204825 z 999999999999").is_none());
+    }
+
+    #[test]
+    fn converts_dates() {
+        assert_eq!(warc_date("20080430204825"), "2008-04-30T20:48:25Z");
+        assert_eq!(warc_date("200804302048"), "200804302048");
+        assert_eq!(warc_date("2008043020482x"), "2008043020482x");
+    }
+
+    #[test]
+    fn reads_records_as_warc() {
+        let filedesc = record("filedesc://example.arc", b"1 0 Example\nURL IP-address Archive-date Content-type Archive-length\n");
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p>hello</p>";
+        let data = [filedesc.clone(), record("http://example.com/", response)].concat();
+        let records = read(data.as_slice());
+        assert_eq!(records.len(), 2);
+
+        let info = records[0].as_ref().ok().unwrap();
+        assert_eq!(info.header("WARC-Type"), Some("warcinfo"));
+        assert_eq!(info.header("Content-Type"), Some("text/html"));
+        assert_eq!(info.offset, 0);
+
+        let capture = records[1].as_ref().ok().unwrap();
+        assert_eq!(capture.header("WARC-Type"), Some("response"));
+        assert_eq!(capture.header("WARC-Target-URI"), Some("http://example.com/"));
+        assert_eq!(capture.header("WARC-Date"), Some("2008-04-30T20:48:25Z"));
+        assert_eq!(capture.header("Content-Type"), Some("application/http; msgtype=response"));
+        assert_eq!(capture.content, response.to_vec());
+        assert_eq!(capture.offset, filedesc.len() as u64);
+    }
+
+    #[test]
+    fn resyncs_after_malformed_headers() {
+        // The block's lines look like headers but don't follow a blank
+        // line, and the absurd length would swallow everything after it.
+        let malformed = b"http://example.com/ bad header\na:b x 20080430204825 z 12\nhttp://example.com/ 0.0.0.0 20080430204825 text/html 999999999\n\n";
+        let data = [malformed.to_vec(), record("http://example.com/next", b"next")].concat();
+        let records = read(data.as_slice());
+        assert_eq!(records.len(), 2);
+        assert!(matches!(records[0], Err(Failure::Malformed(_))));
+        let next = records[1].as_ref().ok().unwrap();
+        assert_eq!(next.header("WARC-Target-URI"), Some("http://example.com/next"));
+        assert_eq!(next.content, b"next");
+    }
+
+    #[test]
+    fn ends_at_truncated_records() {
+        let mut data = [record("http://example.com/a", b"whole"), record("http://example.com/b", b"cut short")].concat();
+        data.truncate(data.len() - 4);
+        let records = read(data.as_slice());
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+        assert!(matches!(records[1], Err(Failure::Fatal(_))));
+    }
+}
@@ -0,0 +1,173 @@
+//! Character set detection and conversion of document bodies to UTF-8,
+//! which is what IEQL's patterns are matched against.
+//!
+//! The encoding is taken from, in order of precedence: a byte order mark,
+//! the `charset` parameter of the `Content-Type`, a `<meta>` declaration
+//! near the start of the document, and finally statistical sniffing.
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+
+/// How far into a document to look for a `<meta>` declaration; the same
+/// limit browsers use.
+const PRESCAN_LENGTH: usize = 1024;
+
+/// How much of a document to feed the sniffer.
+const SNIFF_LENGTH: usize = 64 * 1024;
+
+/// Returns whether documents of this media type are text, and so worth
+/// converting. Anything without a declared type is assumed to be text.
+pub fn is_text(mime: Option<&str>) -> bool {
+    match mime {
+        Some(value) => {
+            value.starts_with("text/")
+                || value.ends_with("+xml")
+                || value.ends_with("/xml")
+                || value.contains("json")
+                || value.contains("javascript")
+        }
+        None => true,
+    }
+}
+
+/// Converts `body` to UTF-8. `content_type` is the full `Content-Type`
+/// value, if any, and `url` is used to improve sniffing.
+pub fn to_utf8(body: Vec<u8>, content_type: Option<&str>, url: Option<&str>) -> Vec<u8> {
+    let encoding = match Encoding::for_bom(body.as_slice()) {
+        Some((encoding, _)) => encoding,
+        None => content_type
+            .and_then(from_content_type)
+            .or_else(|| from_meta(body.as_slice()))
+            .unwrap_or_else(|| sniff(body.as_slice(), url)),
+    };
+    if encoding == UTF_8 && std::str::from_utf8(body.as_slice()).is_ok() {
+        return body;
+    }
+    let (text, _, _) = encoding.decode(body.as_slice());
+    text.into_owned().into_bytes()
+}
+
+/// Reads the `charset` parameter of a `Content-Type` value.
+fn from_content_type(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("charset") {
+            return None;
+        }
+        Encoding::for_label(value.trim().trim_matches(['"', '\'']).as_bytes())
+    })
+}
+
+/// Looks for `<meta charset=...>` or `<meta http-equiv="Content-Type"
+/// content="...; charset=...">` near the start of the document.
+fn from_meta(body: &[u8]) -> Option<&'static Encoding> {
+    let head = body[..body.len().min(PRESCAN_LENGTH)].to_ascii_lowercase();
+    let mut position = 0;
+    while let Some(start) = find(&head[position..], b"<meta") {
+        let tag_start = position + start;
+        let tag_end = tag_start + find(&head[tag_start..], b">").unwrap_or(head.len() - tag_start);
+        let tag = &head[tag_start..tag_end];
+        if let Some(index) = find(tag, b"charset") {
+            let value = tag[index + b"charset".len()..]
+                .iter()
+                .skip_while(|x| x.is_ascii_whitespace())
+                .skip_while(|x| **x == b'=')
+                .skip_while(|x| x.is_ascii_whitespace() || **x == b'"' || **x == b'\'')
+                .take_while(|x| !(x.is_ascii_whitespace() || b"\"';/>".contains(x)))
+                .cloned()
+                .collect::<Vec<u8>>();
+            // As in browsers, a UTF-16 declaration in an ASCII-compatible
+            // document can only really mean UTF-8.
+            if let Some(encoding) = Encoding::for_label(value.as_slice()) {
+                return Some(encoding.output_encoding());
+            }
+        }
+        position = tag_end;
+    }
+    None
+}
+
+/// Guesses the encoding from the content itself, using the top-level
+/// domain of the url as a hint.
+fn sniff(body: &[u8], url: Option<&str>) -> &'static Encoding {
+    let tld = url
+        .and_then(|x| x.split("://").nth(1))
+        .and_then(|x| x.split(['/', ':', '?', '#']).next())
+        .and_then(|x| x.rsplit('.').next())
+        .map(|x| x.to_ascii_lowercase());
+    let mut detector = EncodingDetector::new();
+    let sample = &body[..body.len().min(SNIFF_LENGTH)];
+    detector.feed(sample, sample.len() == body.len());
+    detector.guess(tld.as_deref().map(|x| x.as_bytes()), true)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{KOI8_R, SHIFT_JIS, WINDOWS_1251};
+
+    const RUSSIAN: &str = "Съешь же ещё этих мягких французских булок, да выпей чаю.";
+    const JAPANESE: &str = "いろはにほへと ちりぬるを わかよたれそ つねならむ";
+
+    fn encode(encoding: &'static Encoding, text: &str) -> Vec<u8> {
+        encoding.encode(text).0.into_owned()
+    }
+
+    #[test]
+    fn decodes_the_header_charset() {
+        let body = encode(WINDOWS_1251, RUSSIAN);
+        let text = to_utf8(body, Some("text/html; charset=\"windows-1251\""), None);
+        assert_eq!(String::from_utf8(text).unwrap(), RUSSIAN);
+    }
+
+    #[test]
+    fn decodes_the_meta_charset_without_a_header_one() {
+        let head = "<html><head><meta http-equiv=\"Content-Type\" content=\"text/html; charset=Shift_JIS\"></head><body>";
+        let body = [head.as_bytes(), encode(SHIFT_JIS, JAPANESE).as_slice()].concat();
+        let text = to_utf8(body, Some("text/html"), None);
+        assert_eq!(String::from_utf8(text).unwrap(), format!("{}{}", head, JAPANESE));
+    }
+
+    #[test]
+    fn prefers_the_bom_then_the_header_then_meta() {
+        // The header wins over a `<meta>` declaration.
+        let meta = "<meta charset=shift_jis>";
+        let body = [meta.as_bytes(), encode(WINDOWS_1251, RUSSIAN).as_slice()].concat();
+        let text = to_utf8(body, Some("text/html; charset=windows-1251"), None);
+        assert_eq!(String::from_utf8(text).unwrap(), format!("{}{}", meta, RUSSIAN));
+
+        // A byte order mark wins over both.
+        let utf_16: Vec<u8> = RUSSIAN.encode_utf16().flat_map(|x| x.to_le_bytes()).collect();
+        let body = [&[0xff, 0xfe][..], utf_16.as_slice()].concat();
+        let text = to_utf8(body, Some("text/html; charset=windows-1251"), None);
+        assert_eq!(String::from_utf8(text).unwrap(), RUSSIAN);
+    }
+
+    #[test]
+    fn sniffs_without_declarations() {
+        let body = encode(WINDOWS_1251, RUSSIAN.repeat(4).as_str());
+        let text = to_utf8(body, Some("text/html"), Some("http://example.ru/"));
+        assert_eq!(String::from_utf8(text).unwrap(), RUSSIAN.repeat(4));
+    }
+
+    #[test]
+    fn prescans_meta_declarations() {
+        let tags = "<meta name=\"viewport\" content=\"width=device-width\"><META CHARSET = 'KOI8-R'>";
+        assert_eq!(from_meta(tags.as_bytes()), Some(KOI8_R));
+        // Declarations past the prescan limit are ignored.
+        let late = format!("{}<meta charset=koi8-r>", " ".repeat(PRESCAN_LENGTH));
+        assert_eq!(from_meta(late.as_bytes()), None);
+    }
+
+    #[test]
+    fn reads_utf_16_meta_labels_as_utf_8() {
+        let body = "<meta charset=\"utf-16\"><p>déjà vu</p>";
+        assert_eq!(from_meta(body.as_bytes()), Some(UTF_8));
+        let text = to_utf8(body.as_bytes().to_vec(), None, None);
+        assert_eq!(String::from_utf8(text).unwrap(), body);
+    }
+}
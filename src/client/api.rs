@@ -0,0 +1,494 @@
+//! A typed client for the master's API, with one method per endpoint.
+//! The master wraps what it returns in `{"data": ...}`, or answers with
+//! `{"error": ...}` and a status code; either way the response is checked
+//! against what the client expects, and anything else is a protocol error.
+
+use crate::error::Error;
+use crate::pipeline::{Progress, ScanReport};
+use crate::protocol::{self, ARCHIVE_FORMATS, COMPRESSIONS, IEQL_VERSION, PROTOCOL_VERSION};
+use ieql::output::output::OutputBatch;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a connection to the master.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a request to the master may take in all. Batches of outputs
+/// can be large, so this is generous.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Deserialize)]
+struct Envelope<T> {
+    data: Option<T>,
+    error: Option<String>,
+}
+
+/// What a client tells the master about itself when registering, so
+/// that the master can turn away clients it can't work with.
+#[derive(Serialize)]
+pub struct ClientInfo {
+    protocol: u64,
+    mieql_version: &'static str,
+    ieql_version: &'static str,
+    formats: &'static [&'static str],
+    compressions: &'static [&'static str],
+    threads: u8,
+    host: HostInfo,
+}
+
+/// The machine a client runs on, as far as it can tell.
+#[derive(Serialize)]
+struct HostInfo {
+    hostname: Option<String>,
+    os: Option<String>,
+    os_release: Option<String>,
+    cpus: Option<u32>,
+    memory_kb: Option<u64>,
+}
+
+impl ClientInfo {
+    pub fn new(threads: u8) -> ClientInfo {
+        ClientInfo {
+            protocol: PROTOCOL_VERSION,
+            mieql_version: crate_version!(),
+            ieql_version: IEQL_VERSION,
+            formats: ARCHIVE_FORMATS,
+            compressions: COMPRESSIONS,
+            threads,
+            host: HostInfo {
+                hostname: sys_info::hostname().ok(),
+                os: sys_info::os_type().ok(),
+                os_release: sys_info::os_release().ok(),
+                cpus: sys_info::cpu_num().ok(),
+                memory_kb: sys_info::mem_info().ok().map(|x| x.total),
+            },
+        }
+    }
+}
+
+/// A registration, which names the key it replaces, if any, so that the
+/// master carries its leases over to the new one.
+#[derive(Serialize)]
+struct RegistrationRequest<'a> {
+    #[serde(flatten)]
+    info: &'a ClientInfo,
+    previous_key_id: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Registration {
+    key_id: String,
+    access_key: String,
+    protocol: u64,
+    settings: Settings,
+}
+
+/// The settings the master runs with, as it tells clients on
+/// registration.
+#[derive(Deserialize)]
+pub struct Settings {
+    /// How long a lease lasts without a heartbeat.
+    pub lease_seconds: Option<u64>,
+    /// Whether WET archives are handed out in place of WARCs.
+    #[serde(default)]
+    pub wet: bool,
+    /// How many times a source is attempted before the master gives up.
+    pub max_attempts: Option<u64>,
+    /// How long access keys last.
+    pub key_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct QueryList {
+    queries: Vec<QueryText>,
+}
+
+/// A query as the master stores it, in RON.
+#[derive(Deserialize)]
+pub struct QueryText {
+    pub id: String,
+    pub ieql: String,
+}
+
+/// A source handed out by the master.
+#[derive(Deserialize)]
+pub struct Assignment {
+    /// Where to read the archive from.
+    pub location: String,
+    /// What to call the source when reporting on it.
+    pub id: String,
+    /// How long the lease lasts without a heartbeat. Older masters don't
+    /// lease sources.
+    pub lease_seconds: Option<u64>,
+    /// Where to resume the archive from.
+    #[serde(default)]
+    pub offset: u64,
+}
+
+#[derive(Deserialize)]
+struct OutputReceipt {
+    new_outputs: u64,
+}
+
+#[derive(Deserialize)]
+struct CompletionReceipt {
+    requeued: bool,
+}
+
+/// A scan's progress, sent with each heartbeat.
+#[derive(Serialize)]
+pub struct Heartbeat {
+    records_seen: u64,
+    bytes_read: u64,
+    last_offset: Option<u64>,
+    checkpoint: Option<u64>,
+}
+
+impl Heartbeat {
+    pub fn new(progress: &Progress) -> Heartbeat {
+        Heartbeat {
+            records_seen: progress.records_seen(),
+            bytes_read: progress.bytes_read(),
+            last_offset: progress.last_offset(),
+            checkpoint: progress.checkpoint(),
+        }
+    }
+}
+
+/// The report on a source once it has been scanned. The checkpoint lets
+/// a partial scan be resumed.
+#[derive(Serialize)]
+pub struct CompletionReport {
+    pub status: &'static str,
+    records_seen: u64,
+    records_skipped: u64,
+    bytes_read: u64,
+    last_offset: Option<u64>,
+    checkpoint: Option<u64>,
+    corrupt_members: u64,
+    corrupt_bytes: u64,
+    output_errors: u64,
+    scan_errors: u64,
+    error: Option<String>,
+}
+
+impl CompletionReport {
+    pub fn new(report: &ScanReport, checkpoint: Option<u64>) -> CompletionReport {
+        CompletionReport {
+            status: report.status.as_str(),
+            records_seen: report.records_seen,
+            records_skipped: report.records_skipped,
+            bytes_read: report.bytes_read,
+            last_offset: report.last_offset,
+            checkpoint,
+            corrupt_members: report.corrupt_members,
+            corrupt_bytes: report.corrupt_bytes,
+            output_errors: report.output_errors,
+            scan_errors: report.scan_errors,
+            error: report.error.clone(),
+        }
+    }
+}
+
+/// When to register again before the access key expires.
+pub enum KeyRefresh {
+    /// Shortly before the master says keys expire.
+    Auto,
+    /// Once a key is this old.
+    After(Duration),
+    /// Only once the master rejects the key.
+    Never,
+}
+
+/// The access key in use, shared by every clone of a `MasterClient`.
+struct Credentials {
+    key_id: String,
+    /// The key requests are signed with.
+    access_key: String,
+    issued: Instant,
+    /// How long the master says the key lasts.
+    lifetime: Option<Duration>,
+}
+
+/// A connection to the master. Clones share the connection pool and the
+/// access key, so that a key renewed by one is used by all.
+#[derive(Clone)]
+pub struct MasterClient {
+    http: reqwest::Client,
+    url: reqwest::Url,
+    secret_key: String,
+    info: Arc<ClientInfo>,
+    refresh: Arc<KeyRefresh>,
+    credentials: Arc<Mutex<Credentials>>,
+}
+
+impl MasterClient {
+    /// A client for the master at `url`, which must register before using
+    /// any other endpoint. It registers again by itself whenever the
+    /// master rejects its key, and before the key expires if `refresh`
+    /// says so.
+    pub fn new(url: &str, secret_key: &str, info: ClientInfo, refresh: KeyRefresh) -> Result<MasterClient, Error> {
+        let http = match reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+        {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(Error::Config(format!("unable to create http client: `{}`", error)))
+            }
+        };
+        let url = match reqwest::Url::parse(format!("{}/", url.trim_end_matches('/')).as_str()) {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(Error::Config(format!("invalid master url `{}`: `{}`", url, error)))
+            }
+        };
+        Ok(MasterClient {
+            http,
+            url,
+            secret_key: String::from(secret_key),
+            info: Arc::new(info),
+            refresh: Arc::new(refresh),
+            credentials: Arc::new(Mutex::new(Credentials {
+                key_id: String::new(),
+                access_key: String::new(),
+                issued: Instant::now(),
+                lifetime: None,
+            })),
+        })
+    }
+
+    /// The id of the access key, which is safe to log.
+    pub fn key_id(&self) -> String {
+        self.credentials.lock().unwrap().key_id.clone()
+    }
+
+    /// Registers with the master, replacing the access key in use.
+    /// Returns the protocol version the master speaks and its settings.
+    pub fn register(&self) -> Result<(u64, Settings), Error> {
+        let mut credentials = self.credentials.lock().unwrap();
+        self.register_locked(&mut credentials)
+    }
+
+    fn register_locked(&self, credentials: &mut Credentials) -> Result<(u64, Settings), Error> {
+        let body = RegistrationRequest {
+            info: self.info.as_ref(),
+            previous_key_id: Some(credentials.key_id.as_str()).filter(|x| !x.is_empty()),
+        };
+        let request = self
+            .http
+            .post(self.endpoint_url("/register/")?)
+            .header("Authorization", format!("Bearer {}", self.secret_key))
+            .json(&body);
+        let registration: Registration = self.send("/register/", request)?;
+        *credentials = Credentials {
+            key_id: registration.key_id,
+            access_key: registration.access_key,
+            issued: Instant::now(),
+            lifetime: registration.settings.key_seconds.map(Duration::from_secs),
+        };
+        Ok((registration.protocol, registration.settings))
+    }
+
+    /// Registers again unless another clone already has since the key
+    /// `key_id` was given up on.
+    fn renew(&self, key_id: &str) -> Result<(), Error> {
+        let mut credentials = self.credentials.lock().unwrap();
+        if credentials.key_id != key_id {
+            return Ok(());
+        }
+        self.register_locked(&mut credentials)?;
+        info!("registered again with master: {}", credentials.key_id);
+        Ok(())
+    }
+
+    /// Whether the key in use is old enough to be renewed.
+    fn refresh_due(&self, credentials: &Credentials) -> bool {
+        let age = credentials.issued.elapsed();
+        match (self.refresh.as_ref(), credentials.lifetime) {
+            // Leave a tenth of the lifetime to spare.
+            (KeyRefresh::Auto, Some(lifetime)) => age > lifetime.mul_f64(0.9),
+            (KeyRefresh::After(interval), _) => age > *interval,
+            _ => false,
+        }
+    }
+
+    /// Gives up the access key.
+    pub fn unregister(&self) -> Result<(), Error> {
+        let _: IgnoredAny = self.call(reqwest::Method::GET, "/unregister/", Vec::new())?;
+        Ok(())
+    }
+
+    pub fn queries(&self) -> Result<Vec<QueryText>, Error> {
+        let list: QueryList = self.call(reqwest::Method::GET, "/queries/", Vec::new())?;
+        Ok(list.queries)
+    }
+
+    /// Asks for a source to scan, if there are any left.
+    pub fn next_source(&self) -> Result<Option<Assignment>, Error> {
+        let data: Value = self.call(reqwest::Method::GET, "/source/", Vec::new())?;
+        // An empty queue is an empty object.
+        if data.as_object().map(|x| x.is_empty()).unwrap_or(false) {
+            return Ok(None);
+        }
+        match serde_json::from_value(data) {
+            Ok(value) => Ok(Some(value)),
+            Err(error) => Err(Error::Protocol(format!(
+                "unexpected response from `/source/`: `{}`",
+                error
+            ))),
+        }
+    }
+
+    /// Renews the lease on a source. The master rejects heartbeats for
+    /// sources that are no longer leased to this client.
+    pub fn heartbeat(&self, id: &str, heartbeat: &Heartbeat) -> Result<(), Error> {
+        let endpoint = format!("/heartbeat/{}", protocol::encode_source_id(id));
+        let _: IgnoredAny = self.post(endpoint.as_str(), heartbeat)?;
+        Ok(())
+    }
+
+    /// Sends a batch of outputs, returning how many the master stored.
+    pub fn post_outputs(&self, outputs: &OutputBatch) -> Result<u64, Error> {
+        let receipt: OutputReceipt = self.post("/output/", outputs)?;
+        Ok(receipt.new_outputs)
+    }
+
+    /// Reports on a scanned source, returning whether the master will
+    /// hand it out again.
+    pub fn complete_source(&self, id: &str, report: &CompletionReport) -> Result<bool, Error> {
+        let endpoint = format!("/complete_source/{}", protocol::encode_source_id(id));
+        let receipt: CompletionReceipt = self.post(endpoint.as_str(), report)?;
+        Ok(receipt.requeued)
+    }
+
+    fn endpoint_url(&self, endpoint: &str) -> Result<reqwest::Url, Error> {
+        match self.url.join(endpoint.trim_start_matches('/')) {
+            Ok(value) => Ok(value),
+            Err(error) => Err(Error::Protocol(format!("invalid endpoint `{}`: `{}`", endpoint, error))),
+        }
+    }
+
+    fn post<T: Serialize, R: DeserializeOwned>(&self, endpoint: &str, body: &T) -> Result<R, Error> {
+        let body = match serde_json::to_vec(body) {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(Error::Protocol(format!(
+                    "unable to serialize request to `{}`: `{}`",
+                    endpoint, error
+                )))
+            }
+        };
+        self.call(reqwest::Method::POST, endpoint, body)
+    }
+
+    /// Sends a signed request. If the master rejects the access key, as
+    /// it does once keys expire or the master restarts, the client
+    /// registers again and retries the request once.
+    fn call<T: DeserializeOwned>(&self, method: reqwest::Method, endpoint: &str, body: Vec<u8>) -> Result<T, Error> {
+        let key_id = {
+            let mut credentials = self.credentials.lock().unwrap();
+            if self.refresh_due(&credentials) {
+                info!("access key {} is due for renewal", credentials.key_id);
+                self.register_locked(&mut credentials)?;
+                info!("registered again with master: {}", credentials.key_id);
+            }
+            credentials.key_id.clone()
+        };
+        match self.send(endpoint, self.signed(method.clone(), endpoint, body.clone())?) {
+            Err(error @ Error::Auth(_)) => {
+                warn!("{}; registering again", error);
+                self.renew(key_id.as_str())?;
+                self.send(endpoint, self.signed(method, endpoint, body)?)
+            }
+            result => result,
+        }
+    }
+
+    /// Builds a request signed with the access key. The path signed is
+    /// the one sent, after any percent-encoding.
+    fn signed(&self, method: reqwest::Method, endpoint: &str, body: Vec<u8>) -> Result<reqwest::RequestBuilder, Error> {
+        let url = self.endpoint_url(endpoint)?;
+        let credentials = self.credentials.lock().unwrap();
+        let timestamp = protocol::timestamp();
+        let signature = protocol::sign(
+            credentials.access_key.as_str(),
+            method.as_str(),
+            url.path(),
+            timestamp,
+            body.as_slice(),
+        );
+        let mut request = self
+            .http
+            .request(method, url)
+            .header("X-Key-Id", credentials.key_id.as_str())
+            .header("X-Timestamp", timestamp.to_string())
+            .header("X-Signature", signature);
+        if !body.is_empty() {
+            request = request.header("Content-Type", "application/json");
+        }
+        Ok(request.body(body))
+    }
+
+    /// Sends a request and reads the `data` of the response as a `T`.
+    fn send<T: DeserializeOwned>(&self, endpoint: &str, request: reqwest::RequestBuilder) -> Result<T, Error> {
+        let mut response = match request.send() {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(Error::Network(format!(
+                    "unable to reach master at `{}`: `{}`",
+                    endpoint, error
+                )))
+            }
+        };
+        let status = response.status();
+        let text = match response.text() {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(Error::Network(format!(
+                    "unable to read response from `{}`: `{}`",
+                    endpoint, error
+                )))
+            }
+        };
+        let envelope: Result<Envelope<T>, _> = serde_json::from_str(text.as_str());
+        // A master that is down, or restarting behind a proxy, answers with
+        // a server error, which is worth retrying; anything else it rejects
+        // won't change on a retry.
+        if status.is_server_error() {
+            let error = envelope.ok().and_then(|x| x.error).unwrap_or(text);
+            return Err(Error::Network(format!(
+                "`{}` responded with status {}: {}",
+                endpoint, status, error
+            )));
+        }
+        match envelope {
+            Ok(Envelope { data: Some(data), .. }) => Ok(data),
+            Ok(Envelope { error: Some(error), .. }) if status == reqwest::StatusCode::UNAUTHORIZED => {
+                Err(Error::Auth(format!("`{}` rejected the access key: {}", endpoint, error)))
+            }
+            // The master turns away a wrong secret key as forbidden.
+            Ok(Envelope { error: Some(error), .. })
+                if status == reqwest::StatusCode::FORBIDDEN && endpoint == "/register/" =>
+            {
+                Err(Error::Auth(format!("`{}` rejected the secret key: {}", endpoint, error)))
+            }
+            Ok(Envelope { error: Some(error), .. }) => Err(Error::Protocol(format!(
+                "`{}` responded with status {}: {}",
+                endpoint, status, error
+            ))),
+            Ok(_) => Err(Error::Protocol(format!(
+                "`{}` responded with status {} and no data",
+                endpoint, status
+            ))),
+            Err(error) => Err(Error::Protocol(format!(
+                "unexpected response from `{}` (status {}): `{}`",
+                endpoint, status, error
+            ))),
+        }
+    }
+}
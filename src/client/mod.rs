@@ -0,0 +1,224 @@
+use crate::error::{retry, retry_forever, Error};
+use crate::pipeline::{self, Progress, RecordQueryGroup, ScanEngine, ScanReport};
+use crate::s3::S3Options;
+use crate::source;
+use ieql::query::query::Query;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+mod api;
+
+use api::{ClientInfo, CompletionReport, Heartbeat, MasterClient, QueryText};
+
+pub use api::KeyRefresh;
+
+/// How many times outputs and reports are sent to the master before the
+/// client gives up on them. Requests for work are retried for as long as
+/// the master is unreachable, so that workers outlast its restarts.
+const MASTER_ATTEMPTS: u32 = 8;
+
+/// Loads the queries from the master. Queries that can't be parsed are
+/// skipped, so that one bad query doesn't stop the others.
+fn load_queries(master: &MasterClient) -> Result<Vec<Query>, Error> {
+    let mut queries: Vec<Query> = Vec::new();
+    for text in master.queries()? {
+        match parse_query(text) {
+            Ok(query) => queries.push(query),
+            Err(error) => warn!("{}; skipping it", error),
+        }
+    }
+    Ok(queries)
+}
+
+fn parse_query(text: QueryText) -> Result<Query, Error> {
+    let mut query: Query = match ron::de::from_str(text.ieql.as_str()) {
+        Ok(value) => value,
+        Err(error) => {
+            return Err(Error::Parse(format!(
+                "unable to parse query `{}`: `{}`",
+                text.id, error
+            )))
+        }
+    };
+    query.id = Some(text.id);
+    Ok(query)
+}
+
+/// Heartbeats a source to the master from another thread while it is
+/// scanned, with the scan's progress, so that its lease doesn't expire.
+/// Heartbeats stop once the returned sender is dropped.
+fn start_heartbeats(
+    master: MasterClient,
+    id: String,
+    interval: Duration,
+    progress: Arc<Progress>,
+) -> (Sender<()>, JoinHandle<()>) {
+    let (stop, stopped) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            match master.heartbeat(id.as_str(), &Heartbeat::new(&progress)) {
+                Ok(_) => debug!("sent heartbeat to master"),
+                Err(Error::Protocol(error)) => {
+                    // Another client may be scanning the source too, but
+                    // this scan is still worth finishing and reporting.
+                    warn!("master rejected heartbeat: {}; no longer sending them", error);
+                    break;
+                }
+                Err(error) => warn!("unable to send heartbeat to master: {}", error),
+            }
+        }
+    });
+    (stop, handle)
+}
+
+pub fn main(
+    master_url: String,
+    secret_key: String,
+    threads: u8,
+    options: pipeline::Options,
+    s3_options: S3Options,
+    key_refresh: KeyRefresh,
+) {
+    // Create dataset client
+    let s3_client = match s3_options.client() {
+        Ok(value) => value,
+        Err(error) => Error::Config(error).exit(),
+    };
+    let info = ClientInfo::new(threads);
+    let master = match MasterClient::new(master_url.as_str(), secret_key.as_str(), info, key_refresh) {
+        Ok(value) => value,
+        Err(error) => error.exit(),
+    };
+
+    'primary: loop {
+        // Establish connection & get access key. Once keys expire or the
+        // master forgets them, the client registers again by itself.
+        let (protocol, settings) = match retry_forever(|| master.register()) {
+            Ok(value) => value,
+            Err(error) => error.exit(),
+        };
+
+        info!(
+            "successfully established access key with master: {}",
+            master.key_id()
+        );
+        info!(
+            "master speaks protocol {} (leases of {}, {} attempts per source{})",
+            protocol,
+            settings
+                .lease_seconds
+                .map(|x| format!("{}s", x))
+                .unwrap_or_else(|| String::from("unlimited length")),
+            settings
+                .max_attempts
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| String::from("unlimited")),
+            if settings.wet { ", preferring WET archives" } else { "" }
+        );
+        // Stream and process an archive
+        loop {
+            // Stream loop
+
+            // Get queries
+            let query_vec = match retry_forever(|| load_queries(&master)) {
+                Ok(value) => value,
+                Err(error) => error.exit(),
+            };
+
+            info!(
+                "successfully loaded {} queries from master",
+                query_vec.len()
+            );
+
+            let compiled_query_groups: Vec<RecordQueryGroup> =
+                match pipeline::compile_queries(query_vec, &options) {
+                    Ok(value) => value,
+                    Err(error) => Error::Scan(error).exit(),
+                };
+            if compiled_query_groups.is_empty() {
+                // Scanning without queries would only use up sources.
+                error!("master has no queries that compile; sleeping for five minutes and then trying again...");
+                thread::sleep(Duration::from_millis(60000 * 5));
+                continue;
+            }
+
+            // Create scan engines
+            let scan_engines: Vec<ScanEngine> =
+                pipeline::start_scan_engines(compiled_query_groups, threads);
+            let assignment = match retry_forever(|| master.next_source()) {
+                Ok(Some(value)) => value,
+                Ok(None) => {
+                    error!("data queue is empty; sleeping for five minutes, refreshing authorization, and then trying again...");
+                    thread::sleep(Duration::from_millis(60000 * 5));
+                    match master.unregister() {
+                        Ok(_) => info!("successfully revoked authorization; will try again..."),
+                        Err(err) => error!("unable to revoke authorization: {}", err),
+                    }
+                    continue 'primary;
+                }
+                Err(error) => error.exit(),
+            };
+            let (url_to_stream, data_id, start) = (assignment.location, assignment.id, assignment.offset);
+            info!("found data `{}` to process", url_to_stream);
+            if start > 0 {
+                info!("resuming from checkpoint at byte {}", start);
+            }
+            let progress = Arc::new(Progress::default());
+            // Older masters don't lease sources, so there's nothing to renew.
+            let heartbeats = assignment.lease_seconds.map(|x| {
+                start_heartbeats(
+                    master.clone(),
+                    data_id.clone(),
+                    Duration::from_secs((x / 3).max(1)),
+                    progress.clone(),
+                )
+            });
+            let report = match source::resolve(url_to_stream.as_str(), &s3_client)
+                .and_then(|x| x.open_at(start))
+            {
+                Ok(stream) => pipeline::scan_archive(
+                    stream,
+                    url_to_stream.as_str(),
+                    start,
+                    &scan_engines,
+                    &options,
+                    &progress,
+                    &mut |output_batch| match retry(MASTER_ATTEMPTS, || master.post_outputs(&output_batch)) {
+                        Ok(num) => {
+                            info!("successfully sent {} outputs to master server", num);
+                            Ok(())
+                        }
+                        Err(issue) => {
+                            error!("could not send outputs to master server: {}", issue);
+                            Err(issue.to_string())
+                        }
+                    },
+                ),
+                // The master retries failed sources, so there's no sense
+                // in waiting on this one.
+                Err(error) => {
+                    error!("{}, skipping...", error);
+                    ScanReport::failed(error.to_string())
+                }
+            };
+            if let Some((stop, handle)) = heartbeats {
+                drop(stop);
+                let _ = handle.join();
+            }
+            let report = CompletionReport::new(&report, progress.checkpoint());
+
+            // Report how far the source was scanned; the master requeues
+            // sources that weren't scanned completely.
+            match retry(MASTER_ATTEMPTS, || master.complete_source(data_id.as_str(), &report)) {
+                Ok(true) => info!(
+                    "reported source id `{}` as {}; master will retry it",
+                    data_id, report.status
+                ),
+                Ok(false) => info!("reported source id `{}` as {}", data_id, report.status),
+                Err(error) => error!("unable to report source id `{}`: {}", data_id, error),
+            }
+        }
+    }
+}
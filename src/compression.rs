@@ -0,0 +1,574 @@
+//! Decompression of archive streams. Archives may be stored as-is, gzipped
+//! (one member per record, as Common Crawl does), or zstd-compressed.
+//!
+//! A corrupt gzip member doesn't end the archive: reading resumes at the
+//! next member, and the damage is counted so it can be reported. Member
+//! boundaries are tracked too, so that a scan can later be resumed from
+//! one without decompressing what came before.
+//!
+//! Zstd archives follow the WARC-zstd convention: each record is its own
+//! frame, and the dictionary they were compressed with, if any, is stored
+//! in a skippable frame at the start of the file (itself optionally
+//! zstd-compressed).
+
+use flate2::bufread::GzDecoder;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::rc::Rc;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// The magic number and deflate method that begin every gzip member.
+const GZIP_MEMBER_START: &[u8] = &[0x1f, 0x8b, 0x08];
+/// How much of a member is kept for resyncing within it, should it turn
+/// out to be corrupt. Members are single records, so rarely near this.
+const MAX_RETAINED_MEMBER: usize = 16 * 1024 * 1024;
+/// How many recent member boundaries to remember. Only those just ahead
+/// of the reader are ever looked up.
+const MAX_TRACKED_MEMBERS: usize = 1024;
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// The magic number of the skippable frame holding a WARC-zstd dictionary.
+const ZSTD_DICTIONARY_MAGIC: &[u8] = &[0x5d, 0x2a, 0x4d, 0x18];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Picks the compression from the extension of `location`, if it has
+    /// a known one, and otherwise from the first bytes of the stream.
+    pub fn detect(location: &str, head: &[u8]) -> Compression {
+        let name = location
+            .rsplit('/')
+            .next()
+            .unwrap_or(location)
+            .to_ascii_lowercase();
+        if name.ends_with(".gz") {
+            Compression::Gzip
+        } else if name.ends_with(".zst") {
+            Compression::Zstd
+        } else if name.ends_with(".warc") || name.ends_with(".arc") {
+            Compression::None
+        } else if head.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if head.starts_with(ZSTD_MAGIC) || head.starts_with(ZSTD_DICTIONARY_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Damage skipped while reading an archive.
+#[derive(Clone, Copy, Default)]
+pub struct Skipped {
+    /// Corrupt gzip members that were abandoned.
+    pub members: u64,
+    /// Compressed bytes that were abandoned with them.
+    pub bytes: u64,
+}
+
+/// What a decoder shares with whoever reports on it: the damage it has
+/// skipped, and where its recent gzip members began.
+pub struct DecoderState {
+    compression: Compression,
+    skipped: Skipped,
+    /// The decompressed and compressed offsets at which recent members
+    /// began, oldest first.
+    members: VecDeque<(u64, u64)>,
+}
+
+impl DecoderState {
+    fn new(compression: Compression) -> DecoderState {
+        DecoderState {
+            compression,
+            skipped: Skipped::default(),
+            members: VecDeque::new(),
+        }
+    }
+
+    pub fn skipped(&self) -> Skipped {
+        self.skipped
+    }
+
+    /// Returns the offset in the compressed stream from which decoding
+    /// would yield the decompressed stream from `offset` on, if there is
+    /// one: for gzip, where a member begins at `offset`. Zstd frames
+    /// aren't tracked.
+    pub fn resume_point(&self, offset: u64) -> Option<u64> {
+        match self.compression {
+            Compression::None => Some(offset),
+            Compression::Gzip => self
+                .members
+                .iter()
+                .rev()
+                .find(|(decompressed, _)| *decompressed == offset)
+                .map(|(_, compressed)| *compressed),
+            Compression::Zstd => None,
+        }
+    }
+
+    fn member_started(&mut self, decompressed: u64, compressed: u64) {
+        if self.members.len() >= MAX_TRACKED_MEMBERS {
+            self.members.pop_front();
+        }
+        self.members.push_back((decompressed, compressed));
+    }
+}
+
+pub type SharedDecoderState = Rc<RefCell<DecoderState>>;
+
+/// Wraps `reader` in a decoder for `compression`. `dictionary` is used
+/// for zstd archives that don't carry their own. The returned state is
+/// updated as the archive is decoded.
+pub fn decoder<'a, R: BufRead + 'a>(
+    mut reader: R,
+    compression: Compression,
+    dictionary: Option<&[u8]>,
+) -> Result<(Box<dyn Read + 'a>, SharedDecoderState), String> {
+    let state = Rc::new(RefCell::new(DecoderState::new(compression)));
+    let decoder: Box<dyn Read + 'a> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(ResyncGzDecoder::new(reader, state.clone())),
+        Compression::Zstd => {
+            let embedded = read_dictionary(&mut reader)?;
+            let decoder = match embedded.as_deref().or(dictionary) {
+                Some(value) => zstd::stream::read::Decoder::with_dictionary(reader, value),
+                None => zstd::stream::read::Decoder::with_buffer(reader),
+            };
+            match decoder {
+                Ok(value) => Box::new(value),
+                Err(error) => return Err(format!("unable to start zstd decoder: `{}`", error)),
+            }
+        }
+    };
+    Ok((decoder, state))
+}
+
+/// The error a stream returns, once, when it skips past corrupt data. The
+/// stream itself remains readable from the next intact member.
+#[derive(Debug)]
+pub struct Resynced {
+    pub bytes: u64,
+}
+
+impl fmt::Display for Resynced {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "skipped {} bytes of corrupt gzip data", self.bytes)
+    }
+}
+
+impl Error for Resynced {}
+
+/// Returns whether `error` is a [`Resynced`], after which reading can
+/// continue.
+pub fn is_resync(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .map(|x| x.downcast_ref::<Resynced>().is_some())
+        .unwrap_or(false)
+}
+
+/// Decodes concatenated gzip members one at a time. When a member turns
+/// out to be corrupt, it is abandoned and decoding resumes at the next
+/// member header.
+struct ResyncGzDecoder<R: BufRead> {
+    /// Empty only while moving between members.
+    member: Option<GzDecoder<Counted<R>>>,
+    /// The compressed offset at which the current member began.
+    member_start: u64,
+    /// How many bytes have been decoded.
+    decoded: u64,
+    finished: bool,
+    state: SharedDecoderState,
+}
+
+impl<R: BufRead> ResyncGzDecoder<R> {
+    fn new(reader: R, state: SharedDecoderState) -> ResyncGzDecoder<R> {
+        state.borrow_mut().member_started(0, 0);
+        ResyncGzDecoder {
+            member: Some(GzDecoder::new(Counted::new(reader))),
+            member_start: 0,
+            decoded: 0,
+            finished: false,
+            state,
+        }
+    }
+
+    /// Moves past the current member, or past the corrupt remainder of
+    /// it, and starts decoding the next. Returns whether there is one.
+    fn next_member(&mut self, after_corruption: bool) -> io::Result<bool> {
+        let mut reader = self.member.take().unwrap().into_inner();
+        let result = if after_corruption {
+            // Never resume at the very member that failed.
+            if !reader.rewind_past_mark()
+                && reader.position == self.member_start
+                && !reader.fill_buf()?.is_empty()
+            {
+                reader.consume(1);
+            }
+            reader.skip_to(GZIP_MEMBER_START)
+        } else {
+            reader.fill_buf().map(|x| !x.is_empty())
+        };
+        reader.mark();
+        let position = reader.position;
+        let mut state = self.state.borrow_mut();
+        if after_corruption {
+            state.skipped.members += 1;
+            state.skipped.bytes += position - self.member_start;
+        }
+        if let Ok(true) = result {
+            state.member_started(self.decoded, position);
+        }
+        self.member_start = position;
+        self.member = Some(GzDecoder::new(reader));
+        result
+    }
+}
+
+impl<R: BufRead> Read for ResyncGzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            match self.member.as_mut().unwrap().read(buf) {
+                Ok(0) => {
+                    if !self.next_member(false)? {
+                        self.finished = true;
+                    }
+                }
+                Ok(read) => {
+                    self.decoded += read as u64;
+                    return Ok(read);
+                }
+                Err(error) if is_corruption(&error) => {
+                    let start = self.member_start;
+                    let has_next = self.next_member(true)?;
+                    let bytes = self.member_start - start;
+                    let resumed_at = if has_next {
+                        format!("the next member at {}", self.member_start)
+                    } else {
+                        String::from("the end of the archive")
+                    };
+                    warn!(
+                        "gzip member at {} is corrupt (`{}`); skipped {} bytes to {}",
+                        start, error, bytes, resumed_at
+                    );
+                    self.finished = !has_next;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, Resynced { bytes }));
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+/// Decoding errors, as opposed to failures of the underlying stream.
+fn is_corruption(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::InvalidInput
+        | io::ErrorKind::InvalidData
+        | io::ErrorKind::UnexpectedEof => !is_resync(error),
+        _ => false,
+    }
+}
+
+/// A buffered reader that tracks how far it has been consumed, and can
+/// keep the bytes of the current member so that the search for the next
+/// one can start from just after its beginning. A corrupt header can
+/// otherwise swallow the members after it.
+struct Counted<R: Read> {
+    inner: R,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    position: u64,
+    /// Where in `buffer` the current member began, while it's retained.
+    mark: Option<usize>,
+}
+
+impl<R: Read> Counted<R> {
+    fn new(inner: R) -> Counted<R> {
+        Counted {
+            inner,
+            buffer: vec![0; 64 * 1024],
+            start: 0,
+            end: 0,
+            position: 0,
+            mark: Some(0),
+        }
+    }
+
+    /// Notes the start of a member.
+    fn mark(&mut self) {
+        self.mark = Some(self.start);
+    }
+
+    /// Returns to just after the start of the current member, if it is
+    /// still retained.
+    fn rewind_past_mark(&mut self) -> bool {
+        match self.mark.take() {
+            Some(mark) if mark < self.start => {
+                self.position -= (self.start - mark - 1) as u64;
+                self.start = mark + 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Reads more data, keeping the unconsumed bytes and any retained
+    /// member. Returns the number of bytes read.
+    fn refill(&mut self) -> io::Result<usize> {
+        if self.end == self.buffer.len() {
+            if self.buffer.len() >= MAX_RETAINED_MEMBER {
+                self.mark = None;
+            } else if self.mark.map(|x| x == 0).unwrap_or(false) {
+                let length = self.buffer.len() * 2;
+                self.buffer.resize(length, 0);
+            }
+        }
+        let keep_from = self.mark.unwrap_or(self.start);
+        self.buffer.copy_within(keep_from..self.end, 0);
+        self.start -= keep_from;
+        self.end -= keep_from;
+        self.mark = self.mark.map(|x| x - keep_from);
+        let read = self.inner.read(&mut self.buffer[self.end..])?;
+        self.end += read;
+        Ok(read)
+    }
+
+    /// Consumes bytes up to the next occurrence of `pattern`, leaving it
+    /// unconsumed. Returns whether one was found before the end.
+    fn skip_to(&mut self, pattern: &[u8]) -> io::Result<bool> {
+        loop {
+            let available = &self.buffer[self.start..self.end];
+            if let Some(index) = available.windows(pattern.len()).position(|x| x == pattern) {
+                self.consume(index);
+                return Ok(true);
+            }
+            // Keep a possible partial match at the end, then read more.
+            let keep = (pattern.len() - 1).min(available.len());
+            self.consume(available.len() - keep);
+            if self.refill()? == 0 {
+                let remaining = self.end - self.start;
+                self.consume(remaining);
+                return Ok(false);
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<R: Read> BufRead for Counted<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.start == self.end {
+            self.refill()?;
+        }
+        Ok(&self.buffer[self.start..self.end])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.start += amount;
+        self.position += amount as u64;
+    }
+}
+
+/// Reads the dictionary frame at the start of a WARC-zstd archive, if
+/// there is one, leaving `reader` at the first record.
+fn read_dictionary<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, String> {
+    let head = match reader.fill_buf() {
+        Ok(value) => value,
+        Err(error) => return Err(format!("unable to read archive: `{}`", error)),
+    };
+    if !head.starts_with(ZSTD_DICTIONARY_MAGIC) {
+        return Ok(None);
+    }
+    let mut header = [0u8; 8];
+    let mut dictionary = Vec::new();
+    let result = reader.read_exact(&mut header).and_then(|_| {
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        reader.take(length as u64).read_to_end(&mut dictionary)
+    });
+    if let Err(error) = result {
+        return Err(format!("unable to read zstd dictionary: `{}`", error));
+    }
+    if dictionary.starts_with(ZSTD_MAGIC) {
+        dictionary = match zstd::stream::decode_all(dictionary.as_slice()) {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to decompress zstd dictionary: `{}`", error)),
+        };
+    }
+    debug!("using the archive's {} byte zstd dictionary", dictionary.len());
+    Ok(Some(dictionary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warc::{Failure, Records, WarcReader};
+    use flate2::write::GzEncoder;
+    use std::io::{BufReader, Write};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn record(content: &str) -> Vec<u8> {
+        format!(
+            "WARC/1.0\r\nWARC-Type: response\r\nContent-Length: {}\r\n\r\n{}\r\n\r\n",
+            content.len(),
+            content
+        )
+        .into_bytes()
+    }
+
+    /// Reads a stream to the end, returning what was read and how many
+    /// times it resynced.
+    fn read_all(reader: &mut dyn Read) -> (Vec<u8>, usize) {
+        let mut data = Vec::new();
+        let mut resyncs = 0;
+        let mut buffer = [0; 1024];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => return (data, resyncs),
+                Ok(read) => data.extend_from_slice(&buffer[..read]),
+                Err(error) if is_resync(&error) => resyncs += 1,
+                Err(error) => panic!("unexpected error: {}", error),
+            }
+        }
+    }
+
+    #[test]
+    fn detects_compression() {
+        assert_eq!(Compression::detect("a/b.warc.gz", b""), Compression::Gzip);
+        assert_eq!(Compression::detect("b.warc.zst", b""), Compression::Zstd);
+        assert_eq!(Compression::detect("b.arc", GZIP_MAGIC), Compression::None);
+        assert_eq!(Compression::detect("-", GZIP_MAGIC), Compression::Gzip);
+        assert_eq!(Compression::detect("-", b"WARC/1.0"), Compression::None);
+    }
+
+    /// A raw-content dictionary, which zstd accepts as well as trained
+    /// ones, made of the text the test records repeat.
+    fn dictionary() -> Vec<u8> {
+        record("WARC-zstd dictionary content").repeat(4)
+    }
+
+    /// Compresses each record as its own frame with `dictionary`.
+    fn zstd_frames(records: &[Vec<u8>], dictionary: &[u8]) -> Vec<u8> {
+        let mut compressor = zstd::bulk::Compressor::with_dictionary(3, dictionary).unwrap();
+        records.iter().flat_map(|x| compressor.compress(x).unwrap()).collect()
+    }
+
+    /// The skippable frame that carries a WARC-zstd archive's dictionary.
+    fn dictionary_frame(dictionary: &[u8]) -> Vec<u8> {
+        let mut frame = ZSTD_DICTIONARY_MAGIC.to_vec();
+        frame.extend_from_slice(&(dictionary.len() as u32).to_le_bytes());
+        frame.extend_from_slice(dictionary);
+        frame
+    }
+
+    #[test]
+    fn uses_embedded_zstd_dictionaries() {
+        let records = [record("first"), record("second")];
+        let frames = zstd_frames(&records, dictionary().as_slice());
+        // The dictionary may be stored as is or compressed itself.
+        let compressed = zstd::stream::encode_all(dictionary().as_slice(), 3).unwrap();
+        for stored in [dictionary(), compressed] {
+            let data = [dictionary_frame(stored.as_slice()), frames.clone()].concat();
+            let (mut reader, _) = decoder(data.as_slice(), Compression::Zstd, None).unwrap();
+            assert_eq!(read_all(&mut reader), (records.concat(), 0));
+        }
+    }
+
+    #[test]
+    fn uses_external_zstd_dictionaries() {
+        let records = [record("first"), record("second")];
+        let data = zstd_frames(&records, dictionary().as_slice());
+        let (mut reader, _) = decoder(data.as_slice(), Compression::Zstd, Some(dictionary().as_slice())).unwrap();
+        assert_eq!(read_all(&mut reader), (records.concat(), 0));
+
+        let (mut reader, _) = decoder(data.as_slice(), Compression::Zstd, None).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn tracks_member_boundaries() {
+        let members = [gzip(b"first"), gzip(b"second")];
+        let data = members.concat();
+        let (mut reader, state) = decoder(data.as_slice(), Compression::Gzip, None).unwrap();
+        assert_eq!(read_all(&mut reader), (b"firstsecond".to_vec(), 0));
+        let state = state.borrow();
+        assert_eq!(state.skipped().members, 0);
+        assert_eq!(state.resume_point(0), Some(0));
+        assert_eq!(state.resume_point(5), Some(members[0].len() as u64));
+        assert_eq!(state.resume_point(3), None);
+    }
+
+    #[test]
+    fn skips_a_corrupt_middle_member() {
+        let records = [record("first"), record("second"), record("third")];
+        let mut members: Vec<Vec<u8>> = records.iter().map(|x| gzip(x)).collect();
+        // An invalid block type, just after the gzip header.
+        members[1][10] = 0xff;
+        let data = members.concat();
+        let (reader, state) = decoder(data.as_slice(), Compression::Gzip, None).unwrap();
+        let read: Vec<Result<_, _>> = Records::new(WarcReader::new(BufReader::new(reader))).collect();
+
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].as_ref().ok().unwrap().content, b"first");
+        assert!(matches!(read[1], Err(Failure::Malformed(_))));
+        let third = read[2].as_ref().ok().unwrap();
+        assert_eq!(third.content, b"third");
+        assert_eq!(third.offset, records[0].len() as u64);
+
+        let state = state.borrow();
+        assert_eq!(state.skipped().members, 1);
+        assert_eq!(state.skipped().bytes, members[1].len() as u64);
+        assert_eq!(state.resume_point(0), Some(0));
+        let after_corruption = (members[0].len() + members[1].len()) as u64;
+        assert_eq!(state.resume_point(third.offset), Some(after_corruption));
+    }
+
+    #[test]
+    fn finds_the_member_after_a_truncated_one() {
+        let first = gzip(record("first").as_slice());
+        let mut second = gzip(record("second, which is cut short").as_slice());
+        second.truncate(second.len() / 2);
+        let third = gzip(record("third").as_slice());
+        let data = [first.clone(), second.clone(), third].concat();
+        let (mut reader, state) = decoder(data.as_slice(), Compression::Gzip, None).unwrap();
+        let (decoded, resyncs) = read_all(&mut reader);
+
+        let third_record = record("third");
+        assert_eq!(resyncs, 1);
+        assert!(decoded.starts_with(record("first").as_slice()));
+        assert!(decoded.ends_with(third_record.as_slice()));
+        let state = state.borrow();
+        assert_eq!(state.skipped().members, 1);
+        assert_eq!(state.skipped().bytes, second.len() as u64);
+        let third_offset = (decoded.len() - third_record.len()) as u64;
+        assert_eq!(
+            state.resume_point(third_offset),
+            Some((first.len() + second.len()) as u64)
+        );
+    }
+}
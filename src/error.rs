@@ -0,0 +1,184 @@
+//! The errors a client runs into, and what it does about each: retry the
+//! step that failed, skip the query or source it concerns, or exit with a
+//! code that tells the kinds of failure apart.
+
+use rand::Rng;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+/// The delay before the first retry, which doubles with each attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest delay between attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    /// A connection to the master or to an archive's host failed, timed
+    /// out, or was answered with a server error.
+    Network(String),
+    /// The master rejected a request, or answered with a response the
+    /// client doesn't understand.
+    Protocol(String),
+    /// The master rejected the access key, even after registering again.
+    Auth(String),
+    /// Something from the master, such as a query or an archive location,
+    /// couldn't be parsed.
+    Parse(String),
+    /// S3 refused a request, e.g. because the object is missing or the
+    /// credentials are invalid.
+    S3(String),
+    /// An archive couldn't be opened or decoded.
+    Decode(String),
+    /// The queries couldn't be compiled or run.
+    Scan(String),
+    /// The client's own configuration is invalid.
+    Config(String),
+}
+
+/// What to do about an error.
+#[derive(Debug, PartialEq)]
+pub enum Policy {
+    /// Try again after a backoff, as the failure is likely to pass.
+    Retry,
+    /// Give up on the query or source concerned and carry on.
+    Skip,
+    /// Exit, as nothing more can be done.
+    Fatal,
+}
+
+impl Error {
+    pub fn policy(&self) -> Policy {
+        match self {
+            Error::Network(_) => Policy::Retry,
+            Error::Parse(_) | Error::S3(_) | Error::Decode(_) => Policy::Skip,
+            Error::Protocol(_) | Error::Auth(_) | Error::Scan(_) | Error::Config(_) => Policy::Fatal,
+        }
+    }
+
+    /// The code the client exits with when this error ends it.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Network(_) => 10,
+            Error::Protocol(_) => 11,
+            Error::Parse(_) => 12,
+            Error::S3(_) => 13,
+            Error::Decode(_) => 14,
+            Error::Scan(_) => 15,
+            Error::Config(_) => 16,
+            Error::Auth(_) => 17,
+        }
+    }
+
+    /// Logs the error and exits with its code.
+    pub fn exit(&self) -> ! {
+        error!("{}; exiting", self);
+        std::process::exit(self.exit_code());
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Network(message) => write!(f, "network error: {}", message),
+            Error::Protocol(message) => write!(f, "protocol error: {}", message),
+            Error::Parse(message) => write!(f, "parse error: {}", message),
+            Error::S3(message) => write!(f, "S3 error: {}", message),
+            Error::Decode(message) => write!(f, "decode error: {}", message),
+            Error::Scan(message) => write!(f, "scan error: {}", message),
+            Error::Config(message) => write!(f, "configuration error: {}", message),
+            Error::Auth(message) => write!(f, "authentication error: {}", message),
+        }
+    }
+}
+
+/// The delay before retrying after `attempt` failed attempts: exponential,
+/// capped, and jittered so that clients don't retry in lockstep.
+pub fn backoff(attempt: u32) -> Duration {
+    let ceiling = INITIAL_BACKOFF
+        .checked_mul(1 << attempt.saturating_sub(1).min(16))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF);
+    ceiling.mul_f64(rand::thread_rng().gen_range(0.5, 1.0))
+}
+
+/// Runs `operation` until it succeeds, fails with an error that isn't
+/// worth retrying, or has been attempted `attempts` times.
+pub fn retry<T>(attempts: u32, operation: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    retry_up_to(Some(attempts), operation)
+}
+
+/// Runs `operation` until it succeeds or fails with an error that isn't
+/// worth retrying, however many attempts that takes.
+pub fn retry_forever<T>(operation: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    retry_up_to(None, operation)
+}
+
+fn retry_up_to<T>(attempts: Option<u32>, mut operation: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Err(error)
+                if error.policy() == Policy::Retry && attempts.map(|x| attempt < x).unwrap_or(true) =>
+            {
+                let delay = backoff(attempt);
+                warn!(
+                    "{} (attempt {}{}); retrying in {:.1}s",
+                    error,
+                    attempt,
+                    attempts.map(|x| format!(" of {}", x)).unwrap_or_default(),
+                    delay.as_secs_f64()
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_within_bounds() {
+        assert!(backoff(1) <= INITIAL_BACKOFF);
+        assert!(backoff(1) >= INITIAL_BACKOFF / 2);
+        assert!(backoff(40) <= MAX_BACKOFF);
+        assert!(backoff(40) >= MAX_BACKOFF / 2);
+    }
+
+    #[test]
+    fn retries_only_network_errors() {
+        let mut attempts = 0;
+        let result: Result<(), Error> = retry_forever(|| {
+            attempts += 1;
+            Err(Error::Protocol(String::from("rejected")))
+        });
+        assert!(matches!(result, Err(Error::Protocol(_))));
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let result = retry_forever(|| {
+            attempts += 1;
+            match attempts {
+                1 => Err(Error::Network(String::from("refused"))),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.ok(), Some(2));
+    }
+
+    #[test]
+    fn gives_up_after_its_attempts() {
+        let mut attempts = 0;
+        let result: Result<(), Error> = retry(1, || {
+            attempts += 1;
+            Err(Error::Network(String::from("refused")))
+        });
+        assert!(matches!(result, Err(Error::Network(_))));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,230 @@
+//! Parsing of the HTTP messages stored in WARC `response` records.
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::Read;
+
+/// Most responses carry a few dozen headers; this leaves plenty of room.
+const MAX_HEADERS: usize = 256;
+
+/// Decompressed bodies are cut off at this size, so that a small
+/// compressed record cannot expand without bound.
+const MAX_DECODED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// An HTTP response, split into its status line, headers, and body.
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Parses a complete HTTP response message.
+    pub fn parse(data: &[u8]) -> Result<HttpResponse, String> {
+        let mut headers = vec![httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        let header_length = match response.parse(data) {
+            Ok(httparse::Status::Complete(value)) => value,
+            Ok(httparse::Status::Partial) => {
+                return Err(String::from("incomplete http response headers"))
+            }
+            Err(error) => return Err(format!("invalid http response: `{}`", error)),
+        };
+        let status = match response.code {
+            Some(value) => value,
+            None => return Err(String::from("http response has no status code")),
+        };
+        let headers = response
+            .headers
+            .iter()
+            .map(|header| {
+                (
+                    String::from(header.name),
+                    String::from_utf8_lossy(header.value).trim().to_string(),
+                )
+            })
+            .collect();
+        Ok(HttpResponse {
+            status,
+            headers,
+            body: data[header_length..].to_vec(),
+        })
+    }
+
+    /// Looks up a header; HTTP header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The media type from `Content-Type`, lowercased and without
+    /// parameters such as `charset`.
+    pub fn mime(&self) -> Option<String> {
+        self.header("Content-Type")
+            .and_then(|x| x.split(';').next())
+            .map(|x| x.trim().to_ascii_lowercase())
+            .filter(|x| !x.is_empty())
+    }
+
+    /// Removes the `Transfer-Encoding: chunked` framing and any
+    /// `Content-Encoding` compression from the body.
+    ///
+    /// Archives do not always store bodies as sent: some keep these
+    /// headers after the body was already decoded. Each step therefore
+    /// only applies when the body actually looks encoded, and otherwise
+    /// leaves it untouched.
+    pub fn decode_body(&mut self) {
+        let is_chunked = self
+            .header("Transfer-Encoding")
+            .map(|x| x.to_ascii_lowercase().contains("chunked"))
+            .unwrap_or(false);
+        if is_chunked {
+            match dechunk(self.body.as_slice()) {
+                Some(value) => self.body = value,
+                None => debug!("body is not chunked despite its Transfer-Encoding; leaving as-is"),
+            }
+        }
+
+        let encodings: Vec<String> = match self.header("Content-Encoding") {
+            Some(value) => value
+                .split(',')
+                .map(|x| x.trim().to_ascii_lowercase())
+                .filter(|x| !x.is_empty() && x != "identity")
+                .collect(),
+            None => return,
+        };
+        // Encodings are listed in the order they were applied.
+        for encoding in encodings.iter().rev() {
+            match decompress(encoding.as_str(), self.body.as_slice()) {
+                Some(value) => self.body = value,
+                None => {
+                    debug!(
+                        "body is not `{}`-encoded despite its Content-Encoding; leaving as-is",
+                        encoding
+                    );
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Reassembles a chunked body, or returns `None` if it is not validly
+/// chunked. Chunk extensions and trailers are dropped.
+fn dechunk(data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut position = 0;
+    loop {
+        let line_end = position + data[position..].windows(2).position(|x| x == b"\r\n")?;
+        let size_line = std::str::from_utf8(&data[position..line_end]).ok()?;
+        let size_text = size_line.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_text, 16).ok()?;
+        position = line_end + 2;
+        if size == 0 {
+            return Some(body);
+        }
+        let chunk_end = position.checked_add(size)?;
+        if chunk_end > data.len() {
+            // A truncated final chunk still holds usable content.
+            body.extend_from_slice(&data[position..]);
+            return Some(body);
+        }
+        body.extend_from_slice(&data[position..chunk_end]);
+        position = chunk_end;
+        if data[position..].starts_with(b"\r\n") {
+            position += 2;
+        }
+        if position >= data.len() {
+            return Some(body);
+        }
+    }
+}
+
+/// Decompresses `data` with the named content coding, or returns `None`
+/// if the coding is unknown or the data is not validly encoded.
+fn decompress(encoding: &str, data: &[u8]) -> Option<Vec<u8>> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => {
+            if !data.starts_with(&[0x1f, 0x8b]) {
+                return None;
+            }
+            Box::new(GzDecoder::new(data))
+        }
+        // `deflate` is meant to be zlib-wrapped, but raw deflate is common.
+        "deflate" => match data.first() {
+            Some(byte) if byte & 0x0f == 8 => Box::new(ZlibDecoder::new(data)),
+            _ => Box::new(DeflateDecoder::new(data)),
+        },
+        "br" => Box::new(brotli_decompressor::Decompressor::new(data, 4096)),
+        _ => return None,
+    };
+    let mut decoded = Vec::new();
+    match reader.take(MAX_DECODED_SIZE).read_to_end(&mut decoded) {
+        Ok(_) => Some(decoded),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn dechunks_bodies() {
+        let body = dechunk(b"5;name=value\r\nHello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n");
+        assert_eq!(body.as_deref(), Some(&b"Hello, world"[..]));
+    }
+
+    #[test]
+    fn keeps_truncated_final_chunks() {
+        let body = dechunk(b"5\r\nHello\r\n10\r\n, wor");
+        assert_eq!(body.as_deref(), Some(&b"Hello, wor"[..]));
+    }
+
+    #[test]
+    fn rejects_bodies_that_are_not_chunked() {
+        assert_eq!(dechunk(b"<html>Hello</html>"), None);
+        assert_eq!(dechunk(b"zz\r\nHello\r\n0\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn decompresses_bodies() {
+        assert_eq!(decompress("gzip", gzip(b"Hello").as_slice()).as_deref(), Some(&b"Hello"[..]));
+        assert_eq!(decompress("gzip", b"Hello"), None);
+        assert_eq!(decompress("compress", b"Hello"), None);
+    }
+
+    #[test]
+    fn decodes_chunked_compressed_bodies() {
+        let compressed = gzip(b"<html>Hello</html>");
+        let mut data = format!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Encoding: gzip\r\n\r\n{:x}\r\n",
+            compressed.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(compressed.as_slice());
+        data.extend_from_slice(b"\r\n0\r\n\r\n");
+        let mut response = HttpResponse::parse(data.as_slice()).ok().unwrap();
+        response.decode_body();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"<html>Hello</html>");
+    }
+
+    #[test]
+    fn leaves_bodies_already_decoded() {
+        let data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Encoding: gzip\r\n\r\n<html>Hello</html>";
+        let mut response = HttpResponse::parse(data).ok().unwrap();
+        response.decode_body();
+        assert_eq!(response.body, b"<html>Hello</html>");
+    }
+}
@@ -0,0 +1,1020 @@
+//! The master server hands out queries and archive locations to clients
+//! and collects the outputs they produce. It speaks the protocol that
+//! the client's `MasterClient` expects, rooted at a configurable path
+//! prefix.
+
+mod storage;
+
+use self::storage::Storage;
+use crate::protocol::{self, PROTOCOL_VERSION, SIGNATURE_WINDOW};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_LENGTH;
+use hyper::http::request::Parts;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use ieql::query::query::Query;
+use ieql::ScopeContent;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long an access key lasts. Clients register again shortly before
+/// their keys expire, or once one is rejected.
+const ACCESS_KEY_LIFETIME: Duration = Duration::from_secs(60 * 60 * 48);
+
+/// Sources that are reported partial or failed this many times are left
+/// queued but no longer handed out, so that one bad archive can't occupy
+/// clients forever.
+const MAX_SOURCE_ATTEMPTS: i32 = 3;
+
+/// How long a source waits after an incomplete attempt before it is
+/// handed out again, doubling with each attempt, so that an outage
+/// shorter than that doesn't use up its attempts.
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// The largest request body the master reads. Batches of outputs, which
+/// hold at most an update interval's worth of documents' outputs, are by
+/// far the largest requests.
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// The oldest protocol version the master still serves. Clients before
+/// version 3 sent the secret key in the url and don't sign requests.
+const MIN_PROTOCOL_VERSION: u64 = 3;
+
+struct State {
+    prefix: String,
+    secret_key: String,
+    database: Mutex<Box<dyn Storage>>,
+    /// Access keys issued to clients, by id.
+    access_keys: Mutex<HashMap<String, AccessKey>>,
+    /// The key ids, timestamps, and signatures of requests within the
+    /// signature window, and when they were seen, to catch replays.
+    signatures: Mutex<HashMap<String, SystemTime>>,
+    /// Sources handed out and not yet reported on, by id.
+    leases: Mutex<HashMap<String, Lease>>,
+    /// How long a lease lasts without a heartbeat.
+    lease_timeout: Duration,
+    /// Whether to hand out WET archives when all queries scan only text.
+    prefer_wet: bool,
+}
+
+/// The key a client signs its requests with.
+struct AccessKey {
+    secret: String,
+    issued: SystemTime,
+}
+
+/// A client's claim on a source, kept alive by its heartbeats.
+struct Lease {
+    /// The id of the client's access key.
+    key_id: String,
+    /// The archive location handed out, which may differ from the id.
+    location: String,
+    renewed: SystemTime,
+    /// The progress the client last reported.
+    progress: Value,
+}
+
+pub fn main(
+    bind_address: SocketAddr,
+    prefix: String,
+    database_url: String,
+    secret_key: String,
+    prefer_wet: bool,
+    lease_timeout: Duration,
+) {
+    let mut database = match storage::connect(database_url.as_str()) {
+        Ok(value) => value,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(101);
+        }
+    };
+    let leases = match restore_leases(database.as_mut()) {
+        Ok(value) => value,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(101);
+        }
+    };
+    if !leases.is_empty() {
+        info!("restored {} lease(s) from the database", leases.len());
+    }
+    let state = Arc::new(State {
+        prefix: String::from(prefix.trim_end_matches('/')),
+        secret_key,
+        database: Mutex::new(database),
+        access_keys: Mutex::new(HashMap::new()),
+        signatures: Mutex::new(HashMap::new()),
+        leases: Mutex::new(leases),
+        lease_timeout,
+        prefer_wet,
+    });
+
+    let mut runtime = match tokio::runtime::Runtime::new() {
+        Ok(value) => value,
+        Err(error) => {
+            error!("unable to start runtime: `{}`", error);
+            std::process::exit(101);
+        }
+    };
+    let result = runtime.block_on(async move {
+        let server = match Server::try_bind(&bind_address) {
+            Ok(value) => value,
+            Err(error) => {
+                error!("unable to bind to `{}`: `{}`", bind_address, error);
+                std::process::exit(101);
+            }
+        };
+        info!(
+            "master listening on http://{}{}/",
+            bind_address, &state.prefix
+        );
+        let service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| handle(request, state.clone())))
+            }
+        });
+        server.serve(service).await
+    });
+    if let Err(error) = result {
+        error!("master server failed: `{}`", error);
+        std::process::exit(101);
+    }
+}
+
+/// Loads the leases held when the master last stopped. Their clients
+/// keep scanning while the master is down, so each gets a whole timeout
+/// from now to send its next heartbeat.
+fn restore_leases(database: &mut dyn Storage) -> Result<HashMap<String, Lease>, String> {
+    let leases = database.leases()?;
+    Ok(leases
+        .into_iter()
+        .map(|x| {
+            let lease = Lease {
+                key_id: x.key_id,
+                location: x.location,
+                renewed: SystemTime::now(),
+                progress: json!({}),
+            };
+            (x.url, lease)
+        })
+        .collect())
+}
+
+/// Brings the database named by `database_url` up to the schema this
+/// build of mieql expects.
+pub fn migrate(database_url: String) {
+    let mut database = match storage::open(database_url.as_str()) {
+        Ok(value) => value,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(101);
+        }
+    };
+    match database.migrate() {
+        Ok(applied) => match applied.last() {
+            Some(version) => info!(
+                "applied {} migration(s); schema is now at version {}",
+                applied.len(),
+                version
+            ),
+            None => info!("schema is already up to date"),
+        },
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(101);
+        }
+    }
+}
+
+async fn handle(request: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let body = match read_body(&parts, body).await {
+        Ok(value) => value,
+        Err(response) => return Ok(response),
+    };
+    // Database calls block, so keep them off the reactor.
+    Ok(tokio::task::block_in_place(|| route(&state, &parts, &body)))
+}
+
+/// Reads a request's body. Requests are only authenticated once read, so
+/// bodies over `MAX_BODY_SIZE` are refused before they are buffered.
+async fn read_body(parts: &Parts, mut body: Body) -> Result<Vec<u8>, Response<Body>> {
+    let too_large = || {
+        respond_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("request body is larger than {} bytes", MAX_BODY_SIZE),
+        )
+    };
+    let length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok());
+    if length.map(|x| x > MAX_BODY_SIZE as u64).unwrap_or(false) {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(respond_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("unable to read request body: `{}`", error),
+                ))
+            }
+        };
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn route(state: &State, parts: &Parts, body: &[u8]) -> Response<Body> {
+    let path = match parts.uri.path().strip_prefix(state.prefix.as_str()) {
+        Some(value) => value,
+        None => return respond_error(StatusCode::NOT_FOUND, String::from("not found")),
+    };
+    let segments: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+
+    if let (&Method::POST, ["register"]) = (&parts.method, segments.as_slice()) {
+        return register(state, parts, body);
+    }
+
+    let key_id = match authenticate(state, parts, body) {
+        Ok(value) => value,
+        Err(error) => return respond_error(StatusCode::UNAUTHORIZED, error),
+    };
+
+    match (&parts.method, segments.as_slice()) {
+        (&Method::GET, ["queries"]) => queries(state),
+        (&Method::GET, ["source"]) => source(state, key_id.as_str()),
+        (&Method::POST, ["output"]) => output(state, body),
+        (&Method::POST, ["heartbeat", ..]) => match source_id(path, "heartbeat/") {
+            Some(id) => heartbeat(state, key_id.as_str(), id.as_str(), body),
+            None => respond_error(StatusCode::NOT_FOUND, String::from("not found")),
+        },
+        (&Method::POST, ["complete_source", ..]) => match source_id(path, "complete_source/") {
+            Some(id) => complete_source(state, key_id.as_str(), id.as_str(), body),
+            None => respond_error(StatusCode::NOT_FOUND, String::from("not found")),
+        },
+        (&Method::GET, ["unregister"]) => unregister(state, key_id.as_str()),
+        _ => respond_error(StatusCode::NOT_FOUND, String::from("not found")),
+    }
+}
+
+/// Takes the source id from the rest of `path` after `endpoint`. Clients
+/// percent-encode ids, which may be urls, as a single segment.
+fn source_id(path: &str, endpoint: &str) -> Option<String> {
+    match path.trim_start_matches('/').strip_prefix(endpoint) {
+        Some(segment) if !segment.is_empty() => protocol::decode_source_id(segment),
+        _ => None,
+    }
+}
+
+/// Checks a request's signature, returning the id of the access key it
+/// was signed with. Signatures must be made with a known, unexpired key,
+/// be timestamped within the signature window, and not have been seen
+/// before.
+fn authenticate(state: &State, parts: &Parts, body: &[u8]) -> Result<String, String> {
+    let header = |name: &str| parts.headers.get(name).and_then(|x| x.to_str().ok());
+    let (key_id, timestamp, signature) = match (
+        header("X-Key-Id"),
+        header("X-Timestamp").and_then(|x| x.parse::<u64>().ok()),
+        header("X-Signature"),
+    ) {
+        (Some(key_id), Some(timestamp), Some(signature)) => (key_id, timestamp, signature),
+        _ => return Err(String::from("missing or malformed request signature")),
+    };
+    let secret = match state.access_keys.lock().unwrap().get(key_id) {
+        Some(key) if key.issued.elapsed().map(|x| x < ACCESS_KEY_LIFETIME).unwrap_or(true) => {
+            key.secret.clone()
+        }
+        _ => return Err(String::from("unknown or expired access key")),
+    };
+    let skew = protocol::timestamp().abs_diff(timestamp);
+    if skew > SIGNATURE_WINDOW.as_millis() as u64 {
+        return Err(format!(
+            "request timestamp is {}s away from the master's clock",
+            skew / 1000
+        ));
+    }
+    let method = parts.method.as_str();
+    let path = parts.uri.path();
+    if !protocol::verify(secret.as_str(), method, path, timestamp, body, signature) {
+        return Err(String::from("invalid request signature"));
+    }
+    let mut signatures = state.signatures.lock().unwrap();
+    // Older signatures fall outside the window, so can't be replayed.
+    signatures.retain(|_, seen| match seen.elapsed() {
+        Ok(age) => age < SIGNATURE_WINDOW * 2,
+        Err(_) => true,
+    });
+    let seen = format!("{} {} {}", key_id, timestamp, signature);
+    if signatures.insert(seen, SystemTime::now()).is_some() {
+        warn!("rejected replayed request to `{}`", path);
+        return Err(String::from("replayed request"));
+    }
+    Ok(String::from(key_id))
+}
+
+/// Issues an access key to a client that presents the secret key and can
+/// work with this master. Clients describe themselves in the body.
+fn register(state: &State, parts: &Parts, body: &[u8]) -> Response<Body> {
+    let secret = parts
+        .headers
+        .get("Authorization")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    if !secret.map(|x| protocol::secret_matches(x, state.secret_key.as_str())).unwrap_or(false) {
+        warn!("rejected registration with missing or invalid secret key");
+        return respond_error(StatusCode::FORBIDDEN, String::from("invalid secret key"));
+    }
+    let client: Value = if body.is_empty() {
+        json!({})
+    } else {
+        match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(error) => {
+                return respond_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid registration: `{}`", error),
+                )
+            }
+        }
+    };
+    if let Err(error) = check_client(state, &client) {
+        warn!("rejected registration from client {}: {}", describe_client(&client), error);
+        return respond_error(StatusCode::BAD_REQUEST, error);
+    }
+    let key_id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .collect();
+    let access_key: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .collect();
+    let mut access_keys = state.access_keys.lock().unwrap();
+    access_keys.retain(|_, key| match key.issued.elapsed() {
+        Ok(age) => age < ACCESS_KEY_LIFETIME,
+        Err(_) => true,
+    });
+    access_keys.insert(
+        key_id.clone(),
+        AccessKey {
+            secret: access_key.clone(),
+            issued: SystemTime::now(),
+        },
+    );
+    // A client renewing its key keeps the sources it holds.
+    if let Some(previous) = client["previous_key_id"].as_str() {
+        access_keys.remove(previous);
+    }
+    info!(
+        "registered client {} ({} active)",
+        describe_client(&client),
+        access_keys.len()
+    );
+    drop(access_keys);
+    if let Some(previous) = client["previous_key_id"].as_str() {
+        let mut leases = state.leases.lock().unwrap();
+        for lease in leases.values_mut() {
+            if lease.key_id == previous {
+                lease.key_id = key_id.clone();
+            }
+        }
+        let mut database = state.database.lock().unwrap();
+        if let Err(error) = database.transfer_leases(previous, key_id.as_str()) {
+            error!("{}", error);
+        }
+    }
+    respond(json!({
+        "key_id": key_id,
+        "access_key": access_key,
+        "protocol": PROTOCOL_VERSION,
+        "settings": {
+            "lease_seconds": state.lease_timeout.as_secs(),
+            "wet": state.prefer_wet,
+            "max_attempts": MAX_SOURCE_ATTEMPTS,
+            "key_seconds": ACCESS_KEY_LIFETIME.as_secs(),
+        },
+    }))
+}
+
+/// Checks that a client speaks a protocol this master serves and can read
+/// the archives it hands out.
+fn check_client(state: &State, client: &Value) -> Result<(), String> {
+    let protocol = client["protocol"].as_u64().unwrap_or(1);
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol) {
+        return Err(format!(
+            "unsupported protocol version {} (master speaks {} to {})",
+            protocol, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    // Clients that don't list their formats predate WET support.
+    let formats = match client["formats"].as_array() {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+    let required: &[&str] = if state.prefer_wet { &["warc", "wet"] } else { &["warc"] };
+    match required.iter().find(|x| !formats.iter().any(|y| y.as_str() == Some(**x))) {
+        Some(format) => Err(format!("client can't read {} archives", format.to_ascii_uppercase())),
+        None => Ok(()),
+    }
+}
+
+/// Names a client for the log by what it sent on registration.
+fn describe_client(client: &Value) -> String {
+    let protocol = client["protocol"].as_u64().unwrap_or(1);
+    match client["host"]["hostname"].as_str() {
+        Some(hostname) => format!(
+            "`{}` (protocol {}, mieql {}, ieql {}, {} threads)",
+            hostname,
+            protocol,
+            client["mieql_version"].as_str().unwrap_or("unknown"),
+            client["ieql_version"].as_str().unwrap_or("unknown"),
+            client["threads"]
+        ),
+        None => format!("speaking protocol {}", protocol),
+    }
+}
+
+fn queries(state: &State) -> Response<Body> {
+    let rows = match state.database.lock().unwrap().queries() {
+        Ok(value) => value,
+        Err(error) => return respond_error(StatusCode::INTERNAL_SERVER_ERROR, error),
+    };
+    let mut queries: Vec<Value> = Vec::new();
+    for (row_id, ron_text) in rows {
+        let query: Query = match ron::de::from_str(ron_text.as_str()) {
+            Ok(value) => value,
+            Err(error) => {
+                warn!("skipping query #{} that does not parse (`{}`)", row_id, error);
+                continue;
+            }
+        };
+        // Queries without their own id go by their row's, which stays the
+        // same as queries are added and removed.
+        let id = query.id.unwrap_or_else(|| row_id.to_string());
+        queries.push(json!({ "id": id, "ieql": ron_text }));
+    }
+    respond(json!({ "queries": queries }))
+}
+
+fn source(state: &State, key_id: &str) -> Response<Body> {
+    let text_only = if state.prefer_wet {
+        match text_only(state) {
+            Ok(value) => value,
+            Err(error) => return respond_error(StatusCode::INTERNAL_SERVER_ERROR, error),
+        }
+    } else {
+        false
+    };
+    let mut leases = state.leases.lock().unwrap();
+    let mut database = state.database.lock().unwrap();
+    expire_leases(state, &mut leases, database.as_mut());
+    let exclude: Vec<String> = leases.keys().cloned().collect();
+    match database.next_source(exclude.as_slice(), MAX_SOURCE_ATTEMPTS, unix_time()) {
+        Ok(Some(input)) => {
+            let id = input.url;
+            // The source is still completed by its original id.
+            let location = if text_only {
+                wet_location(id.as_str()).unwrap_or_else(|| id.clone())
+            } else {
+                id.clone()
+            };
+            // Checkpoints only apply to the archive they were taken in.
+            let offset = match input.checkpoint {
+                Some((checkpoint_location, offset)) if checkpoint_location == location => offset,
+                _ => 0,
+            };
+            let leased = database.lease_source(id.as_str(), key_id, location.as_str(), unix_time());
+            if let Err(error) = leased {
+                return respond_error(StatusCode::INTERNAL_SERVER_ERROR, error);
+            }
+            leases.insert(
+                id.clone(),
+                Lease {
+                    key_id: String::from(key_id),
+                    location: location.clone(),
+                    renewed: SystemTime::now(),
+                    progress: json!({}),
+                },
+            );
+            if offset > 0 {
+                info!("handing out source `{}` from byte {}", location, offset);
+            } else {
+                info!("handing out source `{}`", location);
+            }
+            respond(json!({
+                "location": location,
+                "id": id,
+                "lease_seconds": state.lease_timeout.as_secs(),
+                "offset": offset,
+            }))
+        }
+        Ok(None) => respond(json!({})),
+        Err(error) => respond_error(StatusCode::INTERNAL_SERVER_ERROR, error),
+    }
+}
+
+/// Drops leases that have gone a whole timeout without a heartbeat, as
+/// their clients have most likely died, and requeues their sources.
+fn expire_leases(state: &State, leases: &mut HashMap<String, Lease>, database: &mut dyn Storage) {
+    let expired: Vec<String> = leases
+        .iter()
+        .filter(|(_, lease)| match lease.renewed.elapsed() {
+            Ok(age) => age >= state.lease_timeout,
+            Err(_) => false,
+        })
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in expired {
+        let lease = leases.remove(&id).unwrap();
+        if let Err(error) = database.record_report(id.as_str(), "expired", &lease.progress) {
+            error!("{}", error);
+        }
+        let reason = format!("no heartbeat for {}s", state.lease_timeout.as_secs());
+        if let Err(error) = requeue(database, id.as_str(), "lost its lease", reason.as_str()) {
+            error!("{}", error);
+        }
+    }
+}
+
+/// Renews the caller's lease on a source and notes its progress.
+fn heartbeat(state: &State, key_id: &str, id: &str, body: &[u8]) -> Response<Body> {
+    let progress: Value = if body.is_empty() {
+        json!({})
+    } else {
+        match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(error) => {
+                return respond_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid heartbeat: `{}`", error),
+                )
+            }
+        }
+    };
+    match state.leases.lock().unwrap().get_mut(id) {
+        Some(lease) if lease.key_id == key_id => {
+            if let Err(error) = save_checkpoint(state, id, lease, &progress) {
+                return respond_error(StatusCode::INTERNAL_SERVER_ERROR, error);
+            }
+            let renewed = state.database.lock().unwrap().lease_source(
+                id,
+                key_id,
+                lease.location.as_str(),
+                unix_time(),
+            );
+            if let Err(error) = renewed {
+                return respond_error(StatusCode::INTERNAL_SERVER_ERROR, error);
+            }
+            lease.renewed = SystemTime::now();
+            lease.progress = progress;
+            respond(json!({ "id": id, "lease_seconds": state.lease_timeout.as_secs() }))
+        }
+        Some(_) => respond_error(
+            StatusCode::CONFLICT,
+            String::from("source is leased to another client"),
+        ),
+        None => respond_error(
+            StatusCode::CONFLICT,
+            String::from("source is not leased; its lease may have expired"),
+        ),
+    }
+}
+
+/// Returns whether every query scans only extracted text, so that WET
+/// archives hold everything they need.
+fn text_only(state: &State) -> Result<bool, String> {
+    let rows = state.database.lock().unwrap().queries()?;
+    let queries: Vec<Query> = rows
+        .iter()
+        .filter_map(|(_, x)| ron::de::from_str(x.as_str()).ok())
+        .collect();
+    Ok(!queries.is_empty()
+        && queries
+            .iter()
+            .all(|x| x.scope.content == ScopeContent::Text))
+}
+
+/// Maps a Common Crawl WARC location to its WET counterpart, e.g.
+/// `.../segments/<id>/warc/<name>.warc.gz` to
+/// `.../segments/<id>/wet/<name>.warc.wet.gz`. Returns `None` for
+/// locations that don't follow that layout.
+fn wet_location(location: &str) -> Option<String> {
+    let name_start = location.rfind("/warc/")?;
+    let name = location[name_start + "/warc/".len()..].strip_suffix(".warc.gz")?;
+    if name.contains('/') {
+        return None;
+    }
+    Some(format!(
+        "{}/wet/{}.warc.wet.gz",
+        &location[..name_start],
+        name
+    ))
+}
+
+fn output(state: &State, body: &[u8]) -> Response<Body> {
+    let batch: Value = match serde_json::from_slice(body) {
+        Ok(value) => value,
+        Err(error) => {
+            return respond_error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid output batch: `{}`", error),
+            )
+        }
+    };
+    let outputs = match batch["outputs"].as_array() {
+        Some(value) => value,
+        None => {
+            return respond_error(
+                StatusCode::BAD_REQUEST,
+                String::from("output batch has no outputs"),
+            )
+        }
+    };
+    match state.database.lock().unwrap().insert_outputs(outputs.as_slice()) {
+        Ok(count) => respond(json!({ "new_outputs": count })),
+        Err(error) => respond_error(StatusCode::INTERNAL_SERVER_ERROR, error),
+    }
+}
+
+/// Stores the checkpoint in a client's heartbeat or report, if it has a
+/// new one, so that later attempts resume from there.
+fn save_checkpoint(state: &State, id: &str, lease: &Lease, progress: &Value) -> Result<(), String> {
+    let checkpoint = match progress["checkpoint"].as_i64() {
+        Some(value) if value > 0 => value,
+        _ => return Ok(()),
+    };
+    if lease.progress["checkpoint"].as_i64() == Some(checkpoint) {
+        return Ok(());
+    }
+    let mut database = state.database.lock().unwrap();
+    database.checkpoint_source(id, lease.location.as_str(), checkpoint)
+}
+
+/// Takes a client's report on a source. Complete sources leave the queue;
+/// partial and failed ones are requeued. Reports without a body come from
+/// older clients, which only report complete sources.
+fn complete_source(state: &State, key_id: &str, id: &str, body: &[u8]) -> Response<Body> {
+    let report: Value = if body.is_empty() {
+        json!({ "status": "complete" })
+    } else {
+        match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(error) => {
+                return respond_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid source report: `{}`", error),
+                )
+            }
+        }
+    };
+    let status = match report["status"].as_str() {
+        Some(value @ "complete") | Some(value @ "partial") | Some(value @ "failed") => value,
+        _ => {
+            return respond_error(
+                StatusCode::BAD_REQUEST,
+                String::from("source report has no valid status"),
+            )
+        }
+    };
+    let reason = report["error"].as_str().unwrap_or("records were lost");
+    let mut leases = state.leases.lock().unwrap();
+    // A client whose lease expired may report after the source has been
+    // handed to another; that lease stays.
+    let holds_lease = match leases.get(id).filter(|x| x.key_id == key_id) {
+        Some(lease) => {
+            if status != "complete" {
+                if let Err(error) = save_checkpoint(state, id, lease, &report) {
+                    return respond_error(StatusCode::INTERNAL_SERVER_ERROR, error);
+                }
+            }
+            leases.remove(id);
+            true
+        }
+        None => false,
+    };
+    let mut database = state.database.lock().unwrap();
+    if let Err(error) = database.record_report(id, status, &report) {
+        return respond_error(StatusCode::INTERNAL_SERVER_ERROR, error);
+    }
+    if status == "complete" {
+        return match database.complete_source(id) {
+            Ok(_) => {
+                info!("source `{}` completed", id);
+                respond(json!({ "id": id, "requeued": false }))
+            }
+            Err(error) => respond_error(StatusCode::INTERNAL_SERVER_ERROR, error),
+        };
+    }
+    // The attempt was counted when the lease expired, and the source may
+    // be with another client now.
+    if !holds_lease {
+        info!(
+            "source `{}` was reported {} by a client that no longer holds its lease; leaving it as it is",
+            id, status
+        );
+        return respond(json!({ "id": id, "requeued": false }));
+    }
+    let outcome = format!("was reported {}", status);
+    match requeue(database.as_mut(), id, outcome.as_str(), reason) {
+        Ok(requeued) => respond(json!({ "id": id, "requeued": requeued })),
+        Err(error) => respond_error(StatusCode::INTERNAL_SERVER_ERROR, error),
+    }
+}
+
+/// Releases a source's lease and counts an incomplete attempt at it. The
+/// source is handed out again unless it has run out of attempts; returns
+/// whether it will be. `outcome` and `reason` describe what happened, for
+/// the log.
+fn requeue(database: &mut dyn Storage, id: &str, outcome: &str, reason: &str) -> Result<bool, String> {
+    database.release_source(id)?;
+    let attempts = database.requeue_source(id)?;
+    if attempts >= MAX_SOURCE_ATTEMPTS {
+        warn!(
+            "source `{}` {} after {} attempts ({}); giving up on it",
+            id, outcome, attempts, reason
+        );
+        Ok(false)
+    } else {
+        let delay = RETRY_DELAY * 2u32.pow(attempts.max(1) as u32 - 1);
+        database.defer_source(id, unix_time() + delay.as_secs() as i64)?;
+        warn!(
+            "source `{}` {} ({}); requeued after {} of {} attempts, to be retried in {}s",
+            id,
+            outcome,
+            reason,
+            attempts,
+            MAX_SOURCE_ATTEMPTS,
+            delay.as_secs()
+        );
+        Ok(true)
+    }
+}
+
+/// The current time in seconds since the epoch, as retry times are kept.
+fn unix_time() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(value) => value.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+fn unregister(state: &State, key_id: &str) -> Response<Body> {
+    state.access_keys.lock().unwrap().remove(key_id);
+    respond(json!({}))
+}
+
+fn respond(data: Value) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(json!({ "data": data }).to_string()))
+        .unwrap()
+}
+
+fn respond_error(status: StatusCode, error: String) -> Response<Body> {
+    if status.is_server_error() {
+        error!("{}", error);
+    }
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json!({ "error": error }).to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        state_at("sqlite::memory:")
+    }
+
+    /// A fresh SQLite database in a temporary file, for tests that look
+    /// at its rows, and a state backed by it.
+    fn database(name: &str) -> (State, rusqlite::Connection) {
+        let path = std::env::temp_dir().join(format!("mieql-{}-{}.db", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let state = state_at(format!("sqlite:{}", path.display()).as_str());
+        (state, rusqlite::Connection::open(&path).unwrap())
+    }
+
+    fn state_at(database_url: &str) -> State {
+        let mut access_keys = HashMap::new();
+        access_keys.insert(
+            String::from("id"),
+            AccessKey {
+                secret: String::from("key"),
+                issued: SystemTime::now(),
+            },
+        );
+        State {
+            prefix: String::from("/mieql"),
+            secret_key: String::from("secret"),
+            database: Mutex::new(storage::connect(database_url).unwrap()),
+            access_keys: Mutex::new(access_keys),
+            signatures: Mutex::new(HashMap::new()),
+            leases: Mutex::new(HashMap::new()),
+            lease_timeout: Duration::from_secs(60),
+            prefer_wet: false,
+        }
+    }
+
+    fn signed(key_id: &str, timestamp: u64, signature: &str) -> Parts {
+        let (parts, _) = Request::builder()
+            .method("GET")
+            .uri("/mieql/source/")
+            .header("X-Key-Id", key_id)
+            .header("X-Timestamp", timestamp.to_string())
+            .header("X-Signature", signature)
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+    }
+
+    #[test]
+    fn accepts_signed_requests() {
+        let state = state();
+        let timestamp = protocol::timestamp();
+        let signature = protocol::sign("key", "GET", "/mieql/source/", timestamp, b"");
+        let parts = signed("id", timestamp, signature.as_str());
+        assert_eq!(authenticate(&state, &parts, b""), Ok(String::from("id")));
+    }
+
+    #[test]
+    fn rejects_replayed_requests() {
+        let state = state();
+        let timestamp = protocol::timestamp();
+        let signature = protocol::sign("key", "GET", "/mieql/source/", timestamp, b"");
+        let parts = signed("id", timestamp, signature.as_str());
+        assert!(authenticate(&state, &parts, b"").is_ok());
+        assert!(authenticate(&state, &parts, b"").is_err());
+        let upper = signed("id", timestamp, signature.to_uppercase().as_str());
+        assert!(authenticate(&state, &upper, b"").is_err());
+    }
+
+    fn lease(key_id: &str) -> Lease {
+        Lease {
+            key_id: String::from(key_id),
+            location: String::from("a.warc.gz"),
+            renewed: SystemTime::now(),
+            progress: json!({}),
+        }
+    }
+
+    fn attempts(connection: &rusqlite::Connection, url: &str) -> Option<i32> {
+        connection
+            .query_row("SELECT attempts FROM inputs WHERE url = ?", [url], |row| row.get(0))
+            .ok()
+    }
+
+    #[test]
+    fn ignores_partial_reports_without_the_lease() {
+        let (state, connection) = database("stale-report");
+        connection.execute("INSERT INTO inputs (url) VALUES ('a.warc.gz')", []).unwrap();
+        state.leases.lock().unwrap().insert(String::from("a.warc.gz"), lease("other"));
+
+        let response = complete_source(&state, "id", "a.warc.gz", br#"{"status": "partial"}"#);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(attempts(&connection, "a.warc.gz"), Some(0));
+        assert_eq!(state.leases.lock().unwrap()["a.warc.gz"].key_id, "other");
+
+        let response = complete_source(&state, "other", "a.warc.gz", br#"{"status": "partial"}"#);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(attempts(&connection, "a.warc.gz"), Some(1));
+        assert!(state.leases.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn completes_sources_whoever_reports_them() {
+        let (state, connection) = database("complete-report");
+        connection.execute("INSERT INTO inputs (url) VALUES ('a.warc.gz')", []).unwrap();
+        state.leases.lock().unwrap().insert(String::from("a.warc.gz"), lease("other"));
+        complete_source(&state, "id", "a.warc.gz", br#"{"status": "complete"}"#);
+        assert_eq!(attempts(&connection, "a.warc.gz"), None);
+    }
+
+    #[test]
+    fn holds_requeued_sources_back() {
+        let (state, connection) = database("retry-delay");
+        connection.execute("INSERT INTO inputs (url) VALUES ('a.warc.gz')", []).unwrap();
+        let requeued = requeue(state.database.lock().unwrap().as_mut(), "a.warc.gz", "failed", "test");
+        assert_eq!(requeued, Ok(true));
+        source(&state, "id");
+        assert!(state.leases.lock().unwrap().is_empty());
+
+        let retry_after: i64 = connection
+            .query_row("SELECT retry_after FROM inputs", [], |row| row.get(0))
+            .unwrap();
+        assert!(retry_after >= unix_time() + RETRY_DELAY.as_secs() as i64 - 5);
+        connection.execute("UPDATE inputs SET retry_after = 0", []).unwrap();
+        source(&state, "id");
+        assert!(state.leases.lock().unwrap().contains_key("a.warc.gz"));
+    }
+
+    #[test]
+    fn restores_leases_after_a_restart() {
+        let (state, connection) = database("restore-leases");
+        connection.execute("INSERT INTO inputs (url) VALUES ('a.warc.gz')", []).unwrap();
+        source(&state, "old");
+        let body = json!({ "protocol": PROTOCOL_VERSION, "previous_key_id": "old" }).to_string();
+        let (parts, _) = Request::builder()
+            .method("POST")
+            .uri("/mieql/register/")
+            .header("Authorization", "Bearer secret")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let response = register(&state, &parts, body.as_bytes());
+        assert_eq!(response.status(), StatusCode::OK);
+        let key_id = state.leases.lock().unwrap()["a.warc.gz"].key_id.clone();
+        assert_ne!(key_id, "old");
+
+        let leases = restore_leases(state.database.lock().unwrap().as_mut()).unwrap();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases["a.warc.gz"].key_id, key_id);
+        assert_eq!(leases["a.warc.gz"].location, "a.warc.gz");
+
+        complete_source(&state, key_id.as_str(), "a.warc.gz", br#"{"status": "failed"}"#);
+        let leases = restore_leases(state.database.lock().unwrap().as_mut()).unwrap();
+        assert!(leases.is_empty());
+    }
+
+    #[test]
+    fn keeps_query_ids_when_others_are_removed() {
+        let (state, connection) = database("query-ids");
+        let query = r#"Query(response: (kind: Full, include: [Url]), scope: (pattern: (content: ".+", kind: RegEx), content: Text), threshold: (considers: [Trigger("0")], requires: 1, inverse: false), triggers: [(pattern: (content: "a", kind: Raw), id: "0")], id: None)"#;
+        for _ in 0..3 {
+            connection.execute("INSERT INTO queries (ron) VALUES (?)", [query]).unwrap();
+        }
+        connection.execute("DELETE FROM queries WHERE id = 1", []).unwrap();
+        let response = queries(&state);
+        let body = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(hyper::body::to_bytes(response.into_body()))
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let ids: Vec<&str> = body["data"]["queries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["2", "3"]);
+    }
+
+    #[test]
+    fn refuses_oversized_bodies() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (parts, _) = Request::builder()
+            .header("Content-Length", (MAX_BODY_SIZE + 1).to_string())
+            .body(())
+            .unwrap()
+            .into_parts();
+        let response = runtime.block_on(read_body(&parts, Body::from("{}"))).unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without a length, the body is cut off once it is too large.
+        let (parts, _) = Request::builder().body(()).unwrap().into_parts();
+        let body = Body::from(vec![b' '; MAX_BODY_SIZE + 1]);
+        let response = runtime.block_on(read_body(&parts, body)).unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = runtime.block_on(read_body(&parts, Body::from("{}"))).unwrap();
+        assert_eq!(body, b"{}");
+    }
+
+    #[test]
+    fn decodes_source_ids() {
+        let id = "https://example.com/a b.warc.gz?x=1#y";
+        let path = format!("/heartbeat/{}", protocol::encode_source_id(id));
+        assert_eq!(source_id(path.as_str(), "heartbeat/"), Some(String::from(id)));
+        assert_eq!(source_id("/heartbeat/", "heartbeat/"), None);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_stale_timestamps() {
+        let state = state();
+        let timestamp = protocol::timestamp();
+        let signature = protocol::sign("key", "GET", "/mieql/source/", timestamp, b"");
+        assert!(authenticate(&state, &signed("other", timestamp, signature.as_str()), b"").is_err());
+        let stale = timestamp - SIGNATURE_WINDOW.as_millis() as u64 - 1000;
+        let signature = protocol::sign("key", "GET", "/mieql/source/", stale, b"");
+        assert!(authenticate(&state, &signed("id", stale, signature.as_str()), b"").is_err());
+    }
+}
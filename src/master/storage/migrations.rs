@@ -0,0 +1,104 @@
+/// A versioned change to the master's schema. Migrations are applied in
+/// order and never edited once released; to change the schema, append a
+/// new one with the SQL for each backend.
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    pub postgres: &'static str,
+    pub sqlite: &'static str,
+}
+
+/// The first migration uses `IF NOT EXISTS` so that databases created by
+/// hand from the README are adopted as-is.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create queries, outputs, and inputs",
+        postgres: "
+            CREATE TABLE IF NOT EXISTS queries (ron TEXT);
+            CREATE TABLE IF NOT EXISTS outputs (jsonb JSONB);
+            CREATE TABLE IF NOT EXISTS inputs (url TEXT);
+        ",
+        sqlite: "
+            CREATE TABLE IF NOT EXISTS queries (ron TEXT);
+            CREATE TABLE IF NOT EXISTS outputs (jsonb TEXT);
+            CREATE TABLE IF NOT EXISTS inputs (url TEXT);
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "count source attempts and keep completion reports",
+        postgres: "
+            ALTER TABLE inputs ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+            CREATE TABLE source_reports (
+                url TEXT NOT NULL,
+                status TEXT NOT NULL,
+                report JSONB NOT NULL,
+                reported_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+        ",
+        sqlite: "
+            ALTER TABLE inputs ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+            CREATE TABLE source_reports (
+                url TEXT NOT NULL,
+                status TEXT NOT NULL,
+                report TEXT NOT NULL,
+                reported_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "keep checkpoints to resume sources from",
+        postgres: "
+            ALTER TABLE inputs ADD COLUMN checkpoint_location TEXT;
+            ALTER TABLE inputs ADD COLUMN checkpoint_offset BIGINT;
+        ",
+        sqlite: "
+            ALTER TABLE inputs ADD COLUMN checkpoint_location TEXT;
+            ALTER TABLE inputs ADD COLUMN checkpoint_offset INTEGER;
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "hold requeued sources back before retrying them",
+        postgres: "
+            ALTER TABLE inputs ADD COLUMN retry_after BIGINT;
+        ",
+        sqlite: "
+            ALTER TABLE inputs ADD COLUMN retry_after INTEGER;
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "keep leases across restarts of the master",
+        postgres: "
+            ALTER TABLE inputs ADD COLUMN leased_to TEXT;
+            ALTER TABLE inputs ADD COLUMN lease_location TEXT;
+            ALTER TABLE inputs ADD COLUMN lease_renewed_at BIGINT;
+        ",
+        sqlite: "
+            ALTER TABLE inputs ADD COLUMN leased_to TEXT;
+            ALTER TABLE inputs ADD COLUMN lease_location TEXT;
+            ALTER TABLE inputs ADD COLUMN lease_renewed_at INTEGER;
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "give queries stable ids",
+        postgres: "
+            ALTER TABLE queries ADD COLUMN id BIGSERIAL;
+        ",
+        sqlite: "
+            CREATE TABLE queries_with_ids (id INTEGER PRIMARY KEY, ron TEXT);
+            INSERT INTO queries_with_ids (id, ron) SELECT rowid, ron FROM queries ORDER BY rowid;
+            DROP TABLE queries;
+            ALTER TABLE queries_with_ids RENAME TO queries;
+        ",
+    },
+];
+
+/// The schema version this build of mieql expects.
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|x| x.version).unwrap_or(0)
+}
@@ -0,0 +1,119 @@
+//! Persistence for the master's state. Every backend exposes the same
+//! logical schema: `queries (ron)`, `outputs (jsonb)`, `inputs (url)`, and
+//! `source_reports`, kept up to date by the shared list of versioned
+//! migrations.
+
+mod migrations;
+mod postgres;
+mod sqlite;
+
+use serde_json::Value;
+
+/// The operations the master needs from its database.
+pub trait Storage: Send {
+    /// Returns the version of the most recent migration applied, or 0
+    /// for a database that has never been migrated.
+    fn schema_version(&mut self) -> Result<i32, String>;
+
+    /// Applies every pending migration in order, returning the versions
+    /// that were applied.
+    fn migrate(&mut self) -> Result<Vec<i32>, String>;
+
+    /// Returns the id and RON text of every query, in order of id.
+    fn queries(&mut self) -> Result<Vec<(i64, String)>, String>;
+
+    /// Returns an input that is not in `exclude`, has been attempted
+    /// fewer than `max_attempts` times, and isn't held back past `now`
+    /// (in seconds since the epoch), if there is one.
+    fn next_source(&mut self, exclude: &[String], max_attempts: i32, now: i64) -> Result<Option<Input>, String>;
+
+    /// Removes a scanned input from the queue.
+    fn complete_source(&mut self, url: &str) -> Result<u64, String>;
+
+    /// Counts an incomplete attempt at an input, which stays queued.
+    /// Returns the number of attempts so far.
+    fn requeue_source(&mut self, url: &str) -> Result<i32, String>;
+
+    /// Holds an input back from being handed out until `until`, in
+    /// seconds since the epoch.
+    fn defer_source(&mut self, url: &str, until: i64) -> Result<(), String>;
+
+    /// Notes that a scan of an input can be resumed from `offset` bytes
+    /// into the archive at `location`.
+    fn checkpoint_source(&mut self, url: &str, location: &str, offset: i64) -> Result<(), String>;
+
+    /// Returns the inputs that are leased to clients.
+    fn leases(&mut self) -> Result<Vec<StoredLease>, String>;
+
+    /// Notes that an input at `location` is leased to the client with the
+    /// access key `key_id`, which last renewed it at `renewed` (in seconds
+    /// since the epoch).
+    fn lease_source(&mut self, url: &str, key_id: &str, location: &str, renewed: i64) -> Result<(), String>;
+
+    /// Clears an input's lease.
+    fn release_source(&mut self, url: &str) -> Result<(), String>;
+
+    /// Hands every lease held with the access key `from` to `to`.
+    fn transfer_leases(&mut self, from: &str, to: &str) -> Result<(), String>;
+
+    /// Keeps a client's completion report for an input.
+    fn record_report(&mut self, url: &str, status: &str, report: &Value) -> Result<(), String>;
+
+    /// Stores each output as its own row, returning the number stored.
+    fn insert_outputs(&mut self, outputs: &[Value]) -> Result<u64, String>;
+}
+
+/// An input waiting to be scanned.
+pub struct Input {
+    pub url: String,
+    /// The archive location and offset that an earlier attempt reached.
+    pub checkpoint: Option<(String, i64)>,
+}
+
+/// A lease on an input, as kept in the database.
+pub struct StoredLease {
+    pub url: String,
+    /// The id of the access key of the client holding the lease.
+    pub key_id: String,
+    /// The archive location handed out, which may differ from the url.
+    pub location: String,
+}
+
+/// Opens the storage backend named by `url` without checking its schema.
+/// Urls of the form `sqlite:<path>` open (and, if needed, create) an SQLite
+/// database file; anything else is treated as a Postgres connection string.
+pub fn open(url: &str) -> Result<Box<dyn Storage>, String> {
+    match url.strip_prefix("sqlite:") {
+        Some(path) => {
+            let path = path.strip_prefix("//").unwrap_or(path);
+            Ok(Box::new(sqlite::Database::open(path)?))
+        }
+        None => Ok(Box::new(postgres::Database::connect(url)?)),
+    }
+}
+
+/// Opens the storage backend named by `url` and makes sure its schema
+/// matches this build. SQLite files are migrated automatically, since
+/// they belong to a single master; Postgres databases must be migrated
+/// explicitly with `mieql master migrate`.
+pub fn connect(url: &str) -> Result<Box<dyn Storage>, String> {
+    let mut storage = open(url)?;
+    if url.starts_with("sqlite:") {
+        storage.migrate()?;
+    }
+    let version = storage.schema_version()?;
+    let latest = migrations::latest_version();
+    if version < latest {
+        return Err(format!(
+            "database schema is at version {} but this master requires version {}; run `mieql master migrate`",
+            version, latest
+        ));
+    }
+    if version > latest {
+        return Err(format!(
+            "database schema is at version {}, which is newer than this master supports ({}); upgrade mieql",
+            version, latest
+        ));
+    }
+    Ok(storage)
+}
@@ -0,0 +1,231 @@
+use super::migrations::MIGRATIONS;
+use super::{Input, Storage, StoredLease};
+use postgres::{Client, NoTls};
+use serde_json::Value;
+
+const MIGRATIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS mieql_migrations (
+        version INTEGER PRIMARY KEY,
+        description TEXT NOT NULL,
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+";
+
+/// A connection to a Postgres database holding the `queries`, `outputs`,
+/// and `inputs` tables described in the README.
+pub struct Database {
+    client: Client,
+}
+
+impl Database {
+    pub fn connect(url: &str) -> Result<Database, String> {
+        match Client::connect(url, NoTls) {
+            Ok(client) => Ok(Database { client }),
+            Err(error) => Err(format!("unable to connect to database: `{}`", error)),
+        }
+    }
+}
+
+impl Storage for Database {
+    fn schema_version(&mut self) -> Result<i32, String> {
+        let version = self.client.query_one(
+            "SELECT CASE WHEN to_regclass('mieql_migrations') IS NULL THEN 0 ELSE 1 END",
+            &[],
+        );
+        match version {
+            Ok(row) if row.get::<_, i32>(0) == 0 => return Ok(0),
+            Ok(_) => (),
+            Err(error) => return Err(format!("unable to read schema version: `{}`", error)),
+        }
+        match self
+            .client
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM mieql_migrations", &[])
+        {
+            Ok(row) => Ok(row.get(0)),
+            Err(error) => Err(format!("unable to read schema version: `{}`", error)),
+        }
+    }
+
+    fn migrate(&mut self) -> Result<Vec<i32>, String> {
+        // Postgres DDL is transactional, so a failed migration leaves
+        // the schema exactly as it was.
+        let mut transaction = match self.client.transaction() {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to begin transaction: `{}`", error)),
+        };
+        if let Err(error) = transaction.batch_execute(MIGRATIONS_TABLE) {
+            return Err(format!("unable to create migrations table: `{}`", error));
+        }
+        // Keep two masters from migrating the same database at once.
+        if let Err(error) =
+            transaction.batch_execute("LOCK TABLE mieql_migrations IN EXCLUSIVE MODE")
+        {
+            return Err(format!("unable to lock migrations table: `{}`", error));
+        }
+        let current: i32 = match transaction
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM mieql_migrations", &[])
+        {
+            Ok(row) => row.get(0),
+            Err(error) => return Err(format!("unable to read schema version: `{}`", error)),
+        };
+        let mut applied = Vec::new();
+        for migration in MIGRATIONS.iter().filter(|x| x.version > current) {
+            if let Err(error) = transaction.batch_execute(migration.postgres) {
+                return Err(format!(
+                    "unable to apply migration {} ({}): `{}`",
+                    migration.version, migration.description, error
+                ));
+            }
+            if let Err(error) = transaction.execute(
+                "INSERT INTO mieql_migrations (version, description) VALUES ($1, $2)",
+                &[&migration.version, &migration.description],
+            ) {
+                return Err(format!("unable to record migration: `{}`", error));
+            }
+            applied.push(migration.version);
+        }
+        match transaction.commit() {
+            Ok(_) => Ok(applied),
+            Err(error) => Err(format!("unable to commit migrations: `{}`", error)),
+        }
+    }
+
+    fn queries(&mut self) -> Result<Vec<(i64, String)>, String> {
+        match self.client.query("SELECT id, ron FROM queries ORDER BY id", &[]) {
+            Ok(rows) => Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect()),
+            Err(error) => Err(format!("unable to load queries: `{}`", error)),
+        }
+    }
+
+    fn next_source(&mut self, exclude: &[String], max_attempts: i32, now: i64) -> Result<Option<Input>, String> {
+        match self.client.query(
+            "SELECT url, checkpoint_location, checkpoint_offset FROM inputs
+                WHERE NOT (url = ANY($1)) AND attempts < $2
+                AND (retry_after IS NULL OR retry_after <= $3) LIMIT 1",
+            &[&exclude, &max_attempts, &now],
+        ) {
+            Ok(rows) => Ok(rows.first().map(|row| Input {
+                url: row.get(0),
+                checkpoint: row
+                    .get::<_, Option<String>>(1)
+                    .zip(row.get::<_, Option<i64>>(2)),
+            })),
+            Err(error) => Err(format!("unable to load next source: `{}`", error)),
+        }
+    }
+
+    fn complete_source(&mut self, url: &str) -> Result<u64, String> {
+        match self
+            .client
+            .execute("DELETE FROM inputs WHERE url = $1", &[&url])
+        {
+            Ok(removed) => Ok(removed),
+            Err(error) => Err(format!("unable to complete source: `{}`", error)),
+        }
+    }
+
+    fn requeue_source(&mut self, url: &str) -> Result<i32, String> {
+        match self.client.query(
+            "UPDATE inputs SET attempts = attempts + 1 WHERE url = $1 RETURNING attempts",
+            &[&url],
+        ) {
+            Ok(rows) => Ok(rows.iter().map(|row| row.get(0)).max().unwrap_or(0)),
+            Err(error) => Err(format!("unable to requeue source: `{}`", error)),
+        }
+    }
+
+    fn defer_source(&mut self, url: &str, until: i64) -> Result<(), String> {
+        match self.client.execute(
+            "UPDATE inputs SET retry_after = $2 WHERE url = $1",
+            &[&url, &until],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to defer source: `{}`", error)),
+        }
+    }
+
+    fn leases(&mut self) -> Result<Vec<StoredLease>, String> {
+        match self.client.query(
+            "SELECT url, leased_to, COALESCE(lease_location, url) FROM inputs WHERE leased_to IS NOT NULL",
+            &[],
+        ) {
+            Ok(rows) => Ok(rows
+                .iter()
+                .map(|row| StoredLease {
+                    url: row.get(0),
+                    key_id: row.get(1),
+                    location: row.get(2),
+                })
+                .collect()),
+            Err(error) => Err(format!("unable to load leases: `{}`", error)),
+        }
+    }
+
+    fn lease_source(&mut self, url: &str, key_id: &str, location: &str, renewed: i64) -> Result<(), String> {
+        match self.client.execute(
+            "UPDATE inputs SET leased_to = $2, lease_location = $3, lease_renewed_at = $4 WHERE url = $1",
+            &[&url, &key_id, &location, &renewed],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to lease source: `{}`", error)),
+        }
+    }
+
+    fn release_source(&mut self, url: &str) -> Result<(), String> {
+        match self.client.execute(
+            "UPDATE inputs SET leased_to = NULL, lease_location = NULL, lease_renewed_at = NULL WHERE url = $1",
+            &[&url],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to release source: `{}`", error)),
+        }
+    }
+
+    fn transfer_leases(&mut self, from: &str, to: &str) -> Result<(), String> {
+        match self.client.execute(
+            "UPDATE inputs SET leased_to = $2 WHERE leased_to = $1",
+            &[&from, &to],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to transfer leases: `{}`", error)),
+        }
+    }
+
+    fn checkpoint_source(&mut self, url: &str, location: &str, offset: i64) -> Result<(), String> {
+        match self.client.execute(
+            "UPDATE inputs SET checkpoint_location = $2, checkpoint_offset = $3 WHERE url = $1",
+            &[&url, &location, &offset],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to checkpoint source: `{}`", error)),
+        }
+    }
+
+    fn record_report(&mut self, url: &str, status: &str, report: &Value) -> Result<(), String> {
+        match self.client.execute(
+            "INSERT INTO source_reports (url, status, report) VALUES ($1, $2, $3)",
+            &[&url, &status, report],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to record source report: `{}`", error)),
+        }
+    }
+
+    fn insert_outputs(&mut self, outputs: &[Value]) -> Result<u64, String> {
+        let mut transaction = match self.client.transaction() {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to begin transaction: `{}`", error)),
+        };
+        let mut inserted = 0;
+        for output in outputs {
+            match transaction.execute("INSERT INTO outputs (jsonb) VALUES ($1)", &[output]) {
+                Ok(count) => inserted += count,
+                Err(error) => return Err(format!("unable to insert output: `{}`", error)),
+            }
+        }
+        match transaction.commit() {
+            Ok(_) => Ok(inserted),
+            Err(error) => Err(format!("unable to commit outputs: `{}`", error)),
+        }
+    }
+}
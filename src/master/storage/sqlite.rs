@@ -0,0 +1,265 @@
+use super::migrations::MIGRATIONS;
+use super::{Input, Storage, StoredLease};
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use serde_json::Value;
+
+const MIGRATIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS mieql_migrations (
+        version INTEGER PRIMARY KEY,
+        description TEXT NOT NULL,
+        applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+";
+
+/// An SQLite database file holding the same tables as the Postgres
+/// backend. Outputs are stored as JSON text.
+pub struct Database {
+    connection: Connection,
+}
+
+impl Database {
+    /// Opens the database at `path`, creating the file if it does not
+    /// exist yet.
+    pub fn open(path: &str) -> Result<Database, String> {
+        match Connection::open(path) {
+            Ok(connection) => Ok(Database { connection }),
+            Err(error) => Err(format!("unable to open database `{}`: `{}`", path, error)),
+        }
+    }
+}
+
+impl Storage for Database {
+    fn schema_version(&mut self) -> Result<i32, String> {
+        let exists = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'mieql_migrations'",
+                [],
+                |_| Ok(()),
+            )
+            .optional();
+        match exists {
+            Ok(Some(_)) => (),
+            Ok(None) => return Ok(0),
+            Err(error) => return Err(format!("unable to read schema version: `{}`", error)),
+        }
+        match self.connection.query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM mieql_migrations",
+            [],
+            |row| row.get(0),
+        ) {
+            Ok(value) => Ok(value),
+            Err(error) => Err(format!("unable to read schema version: `{}`", error)),
+        }
+    }
+
+    fn migrate(&mut self) -> Result<Vec<i32>, String> {
+        let transaction = match self.connection.transaction() {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to begin transaction: `{}`", error)),
+        };
+        if let Err(error) = transaction.execute_batch(MIGRATIONS_TABLE) {
+            return Err(format!("unable to create migrations table: `{}`", error));
+        }
+        let current: i32 = match transaction.query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM mieql_migrations",
+            [],
+            |row| row.get(0),
+        ) {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to read schema version: `{}`", error)),
+        };
+        let mut applied = Vec::new();
+        for migration in MIGRATIONS.iter().filter(|x| x.version > current) {
+            if let Err(error) = transaction.execute_batch(migration.sqlite) {
+                return Err(format!(
+                    "unable to apply migration {} ({}): `{}`",
+                    migration.version, migration.description, error
+                ));
+            }
+            if let Err(error) = transaction.execute(
+                "INSERT INTO mieql_migrations (version, description) VALUES (?, ?)",
+                params![migration.version, migration.description],
+            ) {
+                return Err(format!("unable to record migration: `{}`", error));
+            }
+            applied.push(migration.version);
+        }
+        match transaction.commit() {
+            Ok(_) => Ok(applied),
+            Err(error) => Err(format!("unable to commit migrations: `{}`", error)),
+        }
+    }
+
+    fn queries(&mut self) -> Result<Vec<(i64, String)>, String> {
+        let mut statement = match self.connection.prepare("SELECT id, ron FROM queries ORDER BY id") {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to load queries: `{}`", error)),
+        };
+        let rows = match statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to load queries: `{}`", error)),
+        };
+        match rows.collect() {
+            Ok(value) => Ok(value),
+            Err(error) => Err(format!("unable to load queries: `{}`", error)),
+        }
+    }
+
+    fn next_source(&mut self, exclude: &[String], max_attempts: i32, now: i64) -> Result<Option<Input>, String> {
+        let placeholders = vec!["?"; exclude.len()].join(", ");
+        let sql = format!(
+            "SELECT url, checkpoint_location, checkpoint_offset FROM inputs
+                WHERE url NOT IN ({}) AND attempts < ?
+                AND (retry_after IS NULL OR retry_after <= ?) LIMIT 1",
+            placeholders
+        );
+        let mut params: Vec<&dyn ToSql> = exclude.iter().map(|x| x as &dyn ToSql).collect();
+        params.push(&max_attempts);
+        params.push(&now);
+        match self
+            .connection
+            .query_row(sql.as_str(), params.as_slice(), |row| {
+                let location: Option<String> = row.get(1)?;
+                let offset: Option<i64> = row.get(2)?;
+                Ok(Input {
+                    url: row.get(0)?,
+                    checkpoint: location.zip(offset),
+                })
+            })
+            .optional()
+        {
+            Ok(value) => Ok(value),
+            Err(error) => Err(format!("unable to load next source: `{}`", error)),
+        }
+    }
+
+    fn complete_source(&mut self, url: &str) -> Result<u64, String> {
+        match self
+            .connection
+            .execute("DELETE FROM inputs WHERE url = ?", params![url])
+        {
+            Ok(removed) => Ok(removed as u64),
+            Err(error) => Err(format!("unable to complete source: `{}`", error)),
+        }
+    }
+
+    fn requeue_source(&mut self, url: &str) -> Result<i32, String> {
+        match self
+            .connection
+            .query_row(
+                "UPDATE inputs SET attempts = attempts + 1 WHERE url = ? RETURNING attempts",
+                params![url],
+                |row| row.get(0),
+            )
+            .optional()
+        {
+            Ok(value) => Ok(value.unwrap_or(0)),
+            Err(error) => Err(format!("unable to requeue source: `{}`", error)),
+        }
+    }
+
+    fn defer_source(&mut self, url: &str, until: i64) -> Result<(), String> {
+        match self.connection.execute(
+            "UPDATE inputs SET retry_after = ? WHERE url = ?",
+            params![until, url],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to defer source: `{}`", error)),
+        }
+    }
+
+    fn leases(&mut self) -> Result<Vec<StoredLease>, String> {
+        let mut statement = match self.connection.prepare(
+            "SELECT url, leased_to, COALESCE(lease_location, url) FROM inputs WHERE leased_to IS NOT NULL",
+        ) {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to load leases: `{}`", error)),
+        };
+        let rows = match statement.query_map([], |row| {
+            Ok(StoredLease {
+                url: row.get(0)?,
+                key_id: row.get(1)?,
+                location: row.get(2)?,
+            })
+        }) {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to load leases: `{}`", error)),
+        };
+        match rows.collect() {
+            Ok(value) => Ok(value),
+            Err(error) => Err(format!("unable to load leases: `{}`", error)),
+        }
+    }
+
+    fn lease_source(&mut self, url: &str, key_id: &str, location: &str, renewed: i64) -> Result<(), String> {
+        match self.connection.execute(
+            "UPDATE inputs SET leased_to = ?, lease_location = ?, lease_renewed_at = ? WHERE url = ?",
+            params![key_id, location, renewed, url],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to lease source: `{}`", error)),
+        }
+    }
+
+    fn release_source(&mut self, url: &str) -> Result<(), String> {
+        match self.connection.execute(
+            "UPDATE inputs SET leased_to = NULL, lease_location = NULL, lease_renewed_at = NULL WHERE url = ?",
+            params![url],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to release source: `{}`", error)),
+        }
+    }
+
+    fn transfer_leases(&mut self, from: &str, to: &str) -> Result<(), String> {
+        match self.connection.execute(
+            "UPDATE inputs SET leased_to = ? WHERE leased_to = ?",
+            params![to, from],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to transfer leases: `{}`", error)),
+        }
+    }
+
+    fn checkpoint_source(&mut self, url: &str, location: &str, offset: i64) -> Result<(), String> {
+        match self.connection.execute(
+            "UPDATE inputs SET checkpoint_location = ?, checkpoint_offset = ? WHERE url = ?",
+            params![location, offset, url],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to checkpoint source: `{}`", error)),
+        }
+    }
+
+    fn record_report(&mut self, url: &str, status: &str, report: &Value) -> Result<(), String> {
+        match self.connection.execute(
+            "INSERT INTO source_reports (url, status, report) VALUES (?, ?, ?)",
+            params![url, status, report.to_string()],
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to record source report: `{}`", error)),
+        }
+    }
+
+    fn insert_outputs(&mut self, outputs: &[Value]) -> Result<u64, String> {
+        let transaction = match self.connection.transaction() {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to begin transaction: `{}`", error)),
+        };
+        let mut inserted = 0;
+        for output in outputs {
+            match transaction.execute(
+                "INSERT INTO outputs (jsonb) VALUES (?)",
+                params![output.to_string()],
+            ) {
+                Ok(count) => inserted += count as u64,
+                Err(error) => return Err(format!("unable to insert output: `{}`", error)),
+            }
+        }
+        match transaction.commit() {
+            Ok(_) => Ok(inserted),
+            Err(error) => Err(format!("unable to commit outputs: `{}`", error)),
+        }
+    }
+}
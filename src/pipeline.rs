@@ -0,0 +1,933 @@
+//! The archive scanning pipeline shared by the distributed client and
+//! local scans: on-the-fly decompression, WARC record reading, and scanning.
+
+use crate::arc::ArcReader;
+use crate::charset;
+use crate::compression::{self, Compression};
+use crate::http::HttpResponse;
+use crate::warc::{Failure, Record, Records, WarcReader};
+use crate::wat;
+use ieql::common::compilation::CompilableTo;
+use ieql::output::output::OutputBatch;
+use ieql::query::query::{CompiledQueryGroup, Query, QueryGroup};
+use ieql::scan::scanner::{AsyncScanInterface, Scanner};
+use ieql::Document;
+use ieql::ScopeContent;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+
+const DOCUMENT_BATCH_SIZE: usize = 64;
+/// How long to wait for scan engines to finish what they've been sent
+/// before taking a checkpoint, and at the end of an archive.
+const CHECKPOINT_DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+const FINAL_DRAIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Settings that control how archives are scanned.
+pub struct Options {
+    /// Reading pauses while any scan engine has this many batches queued.
+    pub queue_size: isize,
+    /// How often, in documents, to push outputs and log progress.
+    pub update_interval: u64,
+    /// If set, only responses with one of these HTTP status codes are scanned.
+    pub statuses: Option<Vec<u16>>,
+    /// If set, only responses whose media type starts with one of these
+    /// prefixes (e.g. `text/`) are scanned.
+    pub mimes: Option<Vec<String>>,
+    /// Whether to convert text documents to UTF-8 before scanning.
+    pub transcode: bool,
+    /// The WARC record types (e.g. `response`, `request`) to scan.
+    pub record_types: Vec<String>,
+    /// Record types to scan instead for particular queries, by query id.
+    pub query_record_types: HashMap<String, Vec<String>>,
+    /// The envelope fields that make up documents from WAT archives, or
+    /// `None` to scan each envelope whole.
+    pub wat_fields: Option<Vec<String>>,
+    /// The dictionary for zstd archives that don't carry their own.
+    pub zstd_dictionary: Option<Vec<u8>>,
+    /// How often to take a checkpoint that the archive can be resumed
+    /// from, or `None` not to.
+    pub checkpoint_interval: Option<Duration>,
+}
+
+impl Options {
+    /// The record types to scan for `query`: its own, if configured, and
+    /// otherwise those of the run.
+    fn record_types_for(&self, query: &Query) -> Vec<String> {
+        let mut types = query
+            .id
+            .as_ref()
+            .and_then(|x| self.query_record_types.get(x))
+            .unwrap_or(&self.record_types)
+            .clone();
+        types.sort();
+        types.dedup();
+        types
+    }
+}
+
+/// A compiled query group and the WARC record types its queries scan.
+pub struct RecordQueryGroup {
+    pub record_types: Vec<String>,
+    pub content: ScopeContent,
+    pub group: CompiledQueryGroup,
+}
+
+/// A running scan engine and the WARC record types it is sent.
+pub struct ScanEngine {
+    pub record_types: Vec<String>,
+    pub content: ScopeContent,
+    pub interface: AsyncScanInterface,
+    /// Each batch sent yields one batch of outputs, so these tell when
+    /// the engine has finished everything it was sent.
+    batches_sent: Cell<u64>,
+    batches_received: Cell<u64>,
+}
+
+/// Groups queries by the record types and content they scan and compiles
+/// each group. Queries that don't compile on their own are skipped, so
+/// that one bad query doesn't stop the others.
+pub fn compile_queries(
+    queries: Vec<Query>,
+    options: &Options,
+) -> Result<Vec<RecordQueryGroup>, String> {
+    let mut query_groups: HashMap<(Vec<String>, ScopeContent), QueryGroup> = HashMap::new();
+
+    for query in queries {
+        if let Err(error) = query.compile() {
+            warn!(
+                "unable to compile query `{}`: {}; skipping it",
+                query.id.as_deref().unwrap_or("(no id)"),
+                error
+            );
+            continue;
+        }
+        let key = (options.record_types_for(&query), query.scope.content);
+        match query_groups.get_mut(&key) {
+            Some(query_group) => {
+                query_group.queries.push(query);
+            }
+            None => {
+                query_groups.insert(
+                    key,
+                    QueryGroup {
+                        optimized_content: query.scope.content,
+                        queries: vec![query],
+                    },
+                );
+            }
+        }
+    }
+
+    query_groups
+        .into_iter()
+        .map(|((record_types, content), query_group)| match query_group.compile() {
+            Ok(group) => Ok(RecordQueryGroup {
+                record_types,
+                content,
+                group,
+            }),
+            Err(error) => Err(format!("unable to compile queries: {}", error)),
+        })
+        .collect()
+}
+
+/// Starts one scan engine per query group, splitting `threads` evenly
+/// between them.
+pub fn start_scan_engines(query_groups: Vec<RecordQueryGroup>, threads: u8) -> Vec<ScanEngine> {
+    let threads_per_group: u8 = (threads / (query_groups.len().max(1) as u8)).max(1);
+    query_groups
+        .into_iter()
+        .map(|x| ScanEngine {
+            record_types: x.record_types,
+            content: x.content,
+            interface: x.group.scan_concurrently(threads_per_group),
+            batches_sent: Cell::new(0),
+            batches_received: Cell::new(0),
+        })
+        .collect()
+}
+
+fn max_queue_size(scan_engines: &[ScanEngine]) -> isize {
+    scan_engines
+        .iter()
+        .map(|x| x.interface.batches_pending_processing())
+        .collect::<Vec<isize>>()
+        .into_iter()
+        .fold(0, |acc, b| acc.max(b))
+}
+
+/// Moves the outputs produced so far by each scan engine into
+/// `output_batch`.
+fn collect_outputs(scan_engines: &[ScanEngine], output_batch: &mut OutputBatch) {
+    for scan_engine in scan_engines {
+        for output in scan_engine.interface.outputs() {
+            scan_engine
+                .batches_received
+                .set(scan_engine.batches_received.get() + 1);
+            output_batch.merge_with(output);
+        }
+    }
+}
+
+/// Hands `output_batch` to `push_outputs` unless it's empty, returning
+/// the number of outputs pushed.
+fn push(
+    output_batch: OutputBatch,
+    push_outputs: &mut dyn FnMut(OutputBatch) -> Result<(), String>,
+) -> Result<usize, String> {
+    let total_outputs = output_batch.outputs.len();
+
+    if !output_batch.outputs.is_empty() {
+        push_outputs(output_batch)?;
+    }
+
+    Ok(total_outputs)
+}
+
+/// Collects the outputs produced so far by each scan engine and hands
+/// them to `push_outputs`, returning the number of outputs collected.
+fn push_new_outputs(
+    scan_engines: &[ScanEngine],
+    push_outputs: &mut dyn FnMut(OutputBatch) -> Result<(), String>,
+) -> Result<usize, String> {
+    let mut output_batch = OutputBatch {
+        outputs: Vec::new(),
+    };
+    collect_outputs(scan_engines, &mut output_batch);
+    push(output_batch, push_outputs)
+}
+
+/// Waits for the scan engines to finish every batch they've been sent,
+/// then pushes all of their outputs. Returns whether they finished
+/// within `timeout`.
+fn drain(
+    scan_engines: &[ScanEngine],
+    push_outputs: &mut dyn FnMut(OutputBatch) -> Result<(), String>,
+    timeout: Duration,
+) -> Result<bool, String> {
+    let mut output_batch = OutputBatch {
+        outputs: Vec::new(),
+    };
+    let start_time = SystemTime::now();
+    let drained = loop {
+        collect_outputs(scan_engines, &mut output_batch);
+        if scan_engines
+            .iter()
+            .all(|x| x.batches_received.get() >= x.batches_sent.get())
+        {
+            break true;
+        }
+        if start_time.elapsed().map(|x| x >= timeout).unwrap_or(false) {
+            break false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    push(output_batch, push_outputs)?;
+    Ok(drained)
+}
+
+/// How much of an archive a scan got through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScanStatus {
+    /// Every record was read.
+    Complete,
+    /// Reading ended early, or some records were lost along the way.
+    Partial,
+    /// The archive could not be read at all.
+    Failed,
+}
+
+impl ScanStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScanStatus::Complete => "complete",
+            ScanStatus::Partial => "partial",
+            ScanStatus::Failed => "failed",
+        }
+    }
+}
+
+/// What happened while scanning one archive.
+pub struct ScanReport {
+    pub status: ScanStatus,
+    /// Records read, whether or not any engine scanned them.
+    pub records_seen: u64,
+    /// Records that could not be read and were skipped.
+    pub records_skipped: u64,
+    /// Batches of outputs that `push_outputs` failed to take.
+    pub output_errors: u64,
+    /// Batches of documents that the scan engines failed to take, or
+    /// hadn't finished scanning when the scan ended.
+    pub scan_errors: u64,
+    /// Bytes read from the source, before decompression.
+    pub bytes_read: u64,
+    /// The offset of the last record read, in the decompressed stream.
+    pub last_offset: Option<u64>,
+    /// Corrupt gzip members that were skipped, and their size.
+    pub corrupt_members: u64,
+    pub corrupt_bytes: u64,
+    /// The error that ended reading early, if any.
+    pub error: Option<String>,
+}
+
+impl ScanReport {
+    /// A report for an archive that has been read to its end so far.
+    fn empty() -> ScanReport {
+        ScanReport {
+            status: ScanStatus::Complete,
+            records_seen: 0,
+            records_skipped: 0,
+            output_errors: 0,
+            scan_errors: 0,
+            bytes_read: 0,
+            last_offset: None,
+            corrupt_members: 0,
+            corrupt_bytes: 0,
+            error: None,
+        }
+    }
+
+    /// A report for an archive that couldn't be opened.
+    pub fn failed(error: String) -> ScanReport {
+        ScanReport {
+            status: ScanStatus::Failed,
+            error: Some(error),
+            ..ScanReport::empty()
+        }
+    }
+}
+
+/// How far a scan has got. It is updated as the archive is read, so that
+/// another thread can report on it.
+#[derive(Default)]
+pub struct Progress {
+    records_seen: AtomicU64,
+    bytes_read: AtomicU64,
+    last_offset: AtomicU64,
+    checkpoint: AtomicU64,
+}
+
+impl Progress {
+    pub fn records_seen(&self) -> u64 {
+        self.records_seen.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    pub fn last_offset(&self) -> Option<u64> {
+        match self.records_seen() {
+            0 => None,
+            _ => Some(self.last_offset.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// The offset in the archive, as stored, that the scan can be resumed
+    /// from without losing any outputs, if it has got past the start.
+    pub fn checkpoint(&self) -> Option<u64> {
+        match self.checkpoint.load(Ordering::Relaxed) {
+            0 => None,
+            value => Some(value),
+        }
+    }
+
+    fn record(&self, offset: u64) {
+        self.last_offset.store(offset, Ordering::Relaxed);
+        self.records_seen.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts the bytes read through it.
+struct Tally<'a, R: Read> {
+    inner: R,
+    progress: &'a Progress,
+}
+
+impl<'a, R: Read> Read for Tally<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.bytes_read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// Streams a WARC or ARC archive through the scan engines, handing
+/// outputs to `push_outputs` as they become available. Returns once the
+/// archive has been read and the scan engines have drained, with a report
+/// of how far reading got. `progress` is kept up to date along the way.
+///
+/// `archive` begins `start` bytes into the archive as stored, at a point
+/// a previous scan checkpointed, or at 0.
+///
+/// The compression and format are taken from the extension of `location`,
+/// if it has a known one, and otherwise from the first bytes of the
+/// archive.
+pub fn scan_archive<'a, R: Read + 'a>(
+    archive: R,
+    location: &str,
+    start: u64,
+    scan_engines: &[ScanEngine],
+    options: &Options,
+    progress: &'a Progress,
+    push_outputs: &mut dyn FnMut(OutputBatch) -> Result<(), String>,
+) -> ScanReport {
+    progress.checkpoint.store(start, Ordering::Relaxed);
+    let mut archive = BufReader::new(Tally {
+        inner: archive,
+        progress,
+    });
+    // If this fails, reading will fail again and be reported below.
+    let compression = Compression::detect(location, archive.fill_buf().unwrap_or(&[]));
+    let dictionary = options.zstd_dictionary.as_deref();
+    let (decoder, state) = match compression::decoder(archive, compression, dictionary) {
+        Ok(value) => value,
+        Err(error) => {
+            error!("unable to read `{}`: {}", location, error);
+            return ScanReport::failed(error);
+        }
+    };
+    let mut reader = BufReader::new(decoder);
+    let is_arc = match format_from_extension(location) {
+        Some(value) => value == Format::Arc,
+        None => reader
+            .fill_buf()
+            .map(|x| x.starts_with(b"filedesc:"))
+            .unwrap_or(false),
+    };
+    let resume_point = |offset| state.borrow().resume_point(offset).map(|x| start + x);
+    let mut report = if is_arc {
+        info!("reading `{}` as an ARC archive", location);
+        let records = Records::new(ArcReader::new(reader));
+        scan_records(records, scan_engines, options, progress, &resume_point, push_outputs)
+    } else {
+        let records = Records::new(WarcReader::new(reader));
+        scan_records(records, scan_engines, options, progress, &resume_point, push_outputs)
+    };
+    let skipped = state.borrow().skipped();
+    report.records_seen = progress.records_seen();
+    report.bytes_read = progress.bytes_read();
+    report.last_offset = progress.last_offset();
+    report.corrupt_members = skipped.members;
+    report.corrupt_bytes = skipped.bytes;
+    if report.records_seen == 0 && (report.error.is_some() || report.records_skipped > 0) {
+        report.status = ScanStatus::Failed;
+    } else if report.error.is_some()
+        || report.records_skipped > 0
+        || report.output_errors > 0
+        || report.scan_errors > 0
+        || skipped.members > 0
+    {
+        report.status = ScanStatus::Partial;
+    }
+    if report.records_skipped > 0 || skipped.members > 0 {
+        warn!(
+            "skipped {} unreadable records in `{}`, and {} corrupt gzip members ({} bytes)",
+            report.records_skipped, location, skipped.members, skipped.bytes
+        );
+    }
+    if let Some(error) = &report.error {
+        warn!(
+            "reading `{}` ended early after {} records (`{}`)",
+            location, report.records_seen, error
+        );
+    }
+    report
+}
+
+#[derive(PartialEq)]
+enum Format {
+    Warc,
+    Arc,
+}
+
+fn format_from_extension(location: &str) -> Option<Format> {
+    let name = location.rsplit('/').next().unwrap_or(location).to_ascii_lowercase();
+    let name = name.trim_end_matches(".gz").trim_end_matches(".zst");
+    if name.ends_with(".warc") {
+        Some(Format::Warc)
+    } else if name.ends_with(".arc") {
+        Some(Format::Arc)
+    } else {
+        None
+    }
+}
+
+/// Scans each record, reporting how many were skipped and whether reading
+/// ended early. Records read and checkpoints are noted in `progress`;
+/// `resume_point` maps a record's offset to the point in the archive it
+/// can be resumed from, if there is one.
+fn scan_records<I: Iterator<Item = Result<Record, Failure>>>(
+    records: I,
+    scan_engines: &[ScanEngine],
+    options: &Options,
+    progress: &Progress,
+    resume_point: &dyn Fn(u64) -> Option<u64>,
+    push_outputs: &mut dyn FnMut(OutputBatch) -> Result<(), String>,
+) -> ScanReport {
+    let queue_size = options.queue_size;
+    let update_interval = options.update_interval;
+
+    // Reset stats
+    let mut documents_processed = 0u64;
+    let mut total_outputs = 0;
+    let start_time = SystemTime::now();
+
+    // Engines scan different record types, so each has its own batch.
+    let mut document_batches: Vec<Vec<Document>> =
+        scan_engines.iter().map(|_| Vec::new()).collect();
+    let mut kind = ArchiveKind::Warc;
+    let mut seen_response = false;
+    let mut report = ScanReport::empty();
+    let mut last_checkpoint = SystemTime::now();
+    for record in records {
+        let mut instances = 1;
+
+        // Check if any queue size is too big
+        let mut currently_processing = max_queue_size(scan_engines);
+        while queue_size <= currently_processing {
+            warn!(
+                "maximum queue sized reached ({} >= {}); sleeping for 5s... (#{})",
+                currently_processing, queue_size, instances
+            );
+            instances += 1;
+            thread::sleep(Duration::from_millis(5000));
+            currently_processing = max_queue_size(scan_engines);
+        }
+
+        let record = match record {
+            Ok(value) => value,
+            Err(Failure::Malformed(error)) => {
+                error!("encountered issue while reading archive (`{}`), skipping...", error);
+                report.records_skipped += 1;
+                continue;
+            }
+            Err(Failure::Fatal(error)) => {
+                report.error = Some(error);
+                continue;
+            }
+        };
+        progress.record(record.offset);
+
+        // Checkpoint before the record, once everything before it has
+        // been scanned and its outputs pushed.
+        let due = match options.checkpoint_interval {
+            Some(interval) => last_checkpoint.elapsed().map(|x| x >= interval).unwrap_or(true),
+            None => false,
+        };
+        if due && report.output_errors == 0 && report.scan_errors == 0 {
+            if let Some(point) = resume_point(record.offset) {
+                for (scan_engine, batch) in scan_engines.iter().zip(document_batches.iter_mut()) {
+                    if !send_documents(scan_engine, std::mem::take(batch)) {
+                        report.scan_errors += 1;
+                    }
+                }
+                match drain(scan_engines, push_outputs, CHECKPOINT_DRAIN_TIMEOUT) {
+                    Ok(true) => {
+                        debug!("checkpointed at byte {}", point);
+                        progress.checkpoint.store(point, Ordering::Relaxed);
+                    }
+                    Ok(false) => warn!("scan engines are taking too long; skipping checkpoint"),
+                    Err(_) => report.output_errors += 1,
+                }
+                last_checkpoint = SystemTime::now();
+            }
+        }
+        let record_type = record.header("WARC-Type").unwrap_or("").to_ascii_lowercase();
+        if kind == ArchiveKind::Warc {
+            kind = detect_kind(&record, seen_response);
+            warn_derivative(kind, scan_engines);
+        }
+        seen_response = seen_response || record_type == "response";
+
+        // Derivative archives stand in for responses, for engines that
+        // don't already scan this record type directly.
+        let stand_in_targets: Vec<usize> = scan_engines
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| {
+                !x.record_types.contains(&record_type) && stands_in(kind, &record_type, x)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        let targets: Vec<usize> = scan_engines
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.record_types.contains(&record_type))
+            .map(|(index, _)| index)
+            .collect();
+        let offset = record.offset;
+        let mut documents: Vec<(Vec<usize>, Document)> = Vec::new();
+        if !stand_in_targets.is_empty() && kind == ArchiveKind::Wat {
+            match wat_to_document(&record, options) {
+                Ok(Some(value)) => documents.push((stand_in_targets.clone(), value)),
+                Ok(None) => (),
+                Err(error) => {
+                    error!(
+                        "encountered issue while parsing record at {} (`{}`), skipping...",
+                        offset, error
+                    );
+                    report.records_skipped += 1;
+                }
+            }
+        }
+        let targets = match kind {
+            // WET conversions are scanned as stored either way.
+            ArchiveKind::Wet => [targets, stand_in_targets].concat(),
+            _ => targets,
+        };
+        if !targets.is_empty() {
+            match warc_to_document(record, record_type.as_str(), options) {
+                Ok(Some(value)) => documents.push((targets, value)),
+                Ok(None) => (),
+                Err(error) => {
+                    error!(
+                        "encountered issue while parsing record at {} (`{}`), skipping...",
+                        offset, error
+                    );
+                    report.records_skipped += 1;
+                }
+            }
+        }
+        if documents.is_empty() {
+            continue;
+        }
+
+        // Send for scanning
+        for (targets, document) in documents {
+            documents_processed += 1;
+            for index in targets {
+                document_batches[index].push(document.clone());
+                if document_batches[index].len() >= DOCUMENT_BATCH_SIZE {
+                    let batch = std::mem::take(&mut document_batches[index]);
+                    if !send_documents(&scan_engines[index], batch) {
+                        report.scan_errors += 1;
+                    }
+                }
+            }
+        }
+
+        if documents_processed.is_multiple_of(update_interval) {
+            let old_outputs = total_outputs;
+            let new_outputs = match push_new_outputs(scan_engines, push_outputs) {
+                Ok(value) => value,
+                Err(_) => {
+                    report.output_errors += 1;
+                    0
+                }
+            };
+            let documents_queued = max_queue_size(scan_engines) * DOCUMENT_BATCH_SIZE as isize;
+            let documents_completed = documents_processed - documents_queued as u64;
+            total_outputs = old_outputs + new_outputs;
+            let mut time_elapsed = SystemTime::now()
+                .duration_since(start_time)
+                .expect("time went backwards!")
+                .as_secs();
+            if time_elapsed == 0 {
+                time_elapsed += 1; // for now...
+            }
+            let docs_per_second = documents_completed / time_elapsed;
+            info!(
+                "[{} docs queued] [{} docs/second] [{} docs done] [{} outputs, Δ{}]",
+                documents_queued, docs_per_second, documents_completed, total_outputs, new_outputs
+            );
+        }
+    }
+    // Send remaining documents
+    for (scan_engine, batch) in scan_engines.iter().zip(document_batches) {
+        if !send_documents(scan_engine, batch) {
+            report.scan_errors += 1;
+        }
+    }
+
+    info!("finished archive; waiting for final documents to be processed...");
+    match drain(scan_engines, push_outputs, FINAL_DRAIN_TIMEOUT) {
+        Ok(true) => (),
+        Ok(false) => {
+            // The outputs of these batches are lost, so the archive
+            // wasn't fully scanned.
+            let unfinished: u64 = scan_engines
+                .iter()
+                .map(|x| x.batches_sent.get().saturating_sub(x.batches_received.get()))
+                .sum();
+            warn!(
+                "graceful cleanup is taking too long, forcing end with {} batches unscanned...",
+                unfinished
+            );
+            report.scan_errors += unfinished.max(1);
+        }
+        Err(_) => report.output_errors += 1,
+    }
+    report
+}
+
+/// Common Crawl publishes two derivatives of each WARC, which hold
+/// something in place of each response.
+#[derive(Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    Warc,
+    /// Extracted text, in `conversion` records.
+    Wet,
+    /// JSON metadata, in `metadata` records.
+    Wat,
+}
+
+/// Works out from `record` whether the archive is a derivative: either
+/// its `warcinfo` names a WET or WAT file, or its first records are
+/// derived ones rather than responses.
+fn detect_kind(record: &Record, seen_response: bool) -> ArchiveKind {
+    let filename = record.header("WARC-Filename").unwrap_or("");
+    let is_json = record
+        .header("Content-Type")
+        .map(|x| x.starts_with("application/json"))
+        .unwrap_or(false);
+    match record.header("WARC-Type") {
+        Some("warcinfo") if filename.contains(".wet") => ArchiveKind::Wet,
+        Some("warcinfo") if filename.contains(".wat") => ArchiveKind::Wat,
+        Some("conversion") if !seen_response => ArchiveKind::Wet,
+        Some("metadata") if !seen_response && is_json => ArchiveKind::Wat,
+        _ => ArchiveKind::Warc,
+    }
+}
+
+fn warn_derivative(kind: ArchiveKind, scan_engines: &[ScanEngine]) {
+    match kind {
+        ArchiveKind::Wet => {
+            info!("archive contains extracted text (WET); scanning conversion records as responses");
+            if scan_engines.iter().any(|x| {
+                x.content != ScopeContent::Text && x.record_types.iter().any(|y| y == "response")
+            }) {
+                warn!("queries that scan raw content have nothing to match in WET archives");
+            }
+        }
+        ArchiveKind::Wat => {
+            info!("archive contains metadata (WAT); scanning its metadata records as responses")
+        }
+        ArchiveKind::Warc => (),
+    }
+}
+
+/// Whether a record of `record_type` in an archive of `kind` is scanned in
+/// place of a response by `scan_engine`. WET text is only useful to
+/// queries that scan text.
+fn stands_in(kind: ArchiveKind, record_type: &str, scan_engine: &ScanEngine) -> bool {
+    let scans_responses = scan_engine.record_types.iter().any(|x| x == "response");
+    match kind {
+        ArchiveKind::Wet => {
+            record_type == "conversion"
+                && scans_responses
+                && scan_engine.content == ScopeContent::Text
+        }
+        ArchiveKind::Wat => record_type == "metadata" && scans_responses,
+        ArchiveKind::Warc => false,
+    }
+}
+
+/// Sends a batch to a scan engine, returning whether it was taken.
+fn send_documents(scan_engine: &ScanEngine, documents: Vec<Document>) -> bool {
+    if documents.is_empty() {
+        return true;
+    }
+    match scan_engine.interface.process(docs_to_doc_reference(documents)) {
+        Ok(_) => {
+            scan_engine
+                .batches_sent
+                .set(scan_engine.batches_sent.get() + 1);
+            true
+        }
+        Err(_) => {
+            error!("unable to scan document batch!");
+            false
+        }
+    }
+}
+
+fn docs_to_doc_reference(
+    docs: Vec<Document>,
+) -> ieql::input::document::DocumentReferenceBatch {
+    let mut doc_references: Vec<ieql::input::document::DocumentReference> = Vec::new();
+    for item in docs {
+        doc_references.push(ieql::input::document::DocumentReference::Populated(item));
+    }
+    ieql::input::document::DocumentReferenceBatch::from(doc_references)
+}
+
+/// Builds the document for a WAT `metadata` record from the configured
+/// fields of its envelope, or returns `None` if it doesn't describe a
+/// response or is excluded by the filters.
+fn wat_to_document(record: &Record, options: &Options) -> Result<Option<Document>, String> {
+    let envelope = wat::Envelope::parse(record.content.as_slice())?;
+    if !envelope.is_response() {
+        return Ok(None);
+    }
+    if let Some(statuses) = &options.statuses {
+        if !envelope.status().map(|x| statuses.contains(&x)).unwrap_or(false) {
+            return Ok(None);
+        }
+    }
+    if let Some(mimes) = &options.mimes {
+        let mime = envelope.mime().unwrap_or_default();
+        if !mimes.iter().any(|x| mime.starts_with(x.as_str())) {
+            return Ok(None);
+        }
+    }
+    let url = envelope
+        .target_uri()
+        .or_else(|| record.header("WARC-Target-URI"))
+        .map(String::from);
+    let (data, mime) = match &options.wat_fields {
+        Some(fields) => (envelope.extract(fields.as_slice()), "text/plain"),
+        None => (record.content.clone(), "application/json"),
+    };
+    Ok(Some(Document {
+        data,
+        url,
+        mime: Some(String::from(mime)),
+    }))
+}
+
+/// Builds the document for a record, or returns `None` if it is excluded
+/// by the status or media type filters, which apply only to responses.
+///
+/// For `response` records holding HTTP, the document is the response body
+/// and has the response's media type. `request` records are scanned whole,
+/// headers included, as `application/http`. Other records (`metadata`,
+/// `resource`, `conversion`, and non-HTTP responses) are scanned as stored,
+/// with the record's own `Content-Type`, as are responses whose HTTP
+/// doesn't parse.
+fn warc_to_document(
+    record: Record,
+    record_type: &str,
+    options: &Options,
+) -> Result<Option<Document>, String> {
+    let url = record.header("WARC-Target-URI").map(String::from);
+    let is_http = match record.header("Content-Type") {
+        Some(value) => value.to_ascii_lowercase().starts_with("application/http"),
+        None => record_type == "response",
+    };
+    // Responses that don't parse as HTTP are still scanned, as stored.
+    let response = if is_http && record_type == "response" {
+        match HttpResponse::parse(record.content.as_slice()) {
+            Ok(value) => Some(value),
+            Err(error) => {
+                warn!(
+                    "unable to parse HTTP response in record at {} (`{}`), scanning it as stored",
+                    record.offset, error
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let (status, content_type, mime, data) = if let Some(mut response) = response {
+        response.decode_body();
+        let content_type = response.header("Content-Type").map(String::from);
+        // Without a declared type, HTML is most likely; in any case, it's a safe bet.
+        let mime = response.mime().unwrap_or_else(|| String::from("text/html"));
+        (Some(response.status), content_type, Some(mime), response.body)
+    } else {
+        let content_type = record.header("Content-Type").map(String::from);
+        let mime = content_type
+            .as_ref()
+            .and_then(|x| x.split(';').next())
+            .map(|x| x.trim().to_ascii_lowercase())
+            .filter(|x| !x.is_empty());
+        (None, content_type, mime, record.content)
+    };
+
+    // Both filters concern responses; other records are scanned whatever
+    // their type.
+    if let (Some(statuses), "response") = (&options.statuses, record_type) {
+        if !status.map(|x| statuses.contains(&x)).unwrap_or(false) {
+            return Ok(None);
+        }
+    }
+    if let (Some(mimes), "response") = (&options.mimes, record_type) {
+        let mime = mime.as_deref().unwrap_or("");
+        if !mimes.iter().any(|x| mime.starts_with(x.as_str())) {
+            return Ok(None);
+        }
+    }
+
+    let data = if options.transcode && charset::is_text(mime.as_deref()) {
+        charset::to_utf8(data, content_type.as_deref(), url.as_deref())
+    } else {
+        data
+    };
+
+    Ok(Some(Document { data, url, mime }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> Options {
+        Options {
+            queue_size: 256,
+            update_interval: 512,
+            statuses: None,
+            mimes: None,
+            transcode: false,
+            record_types: vec![String::from("response")],
+            query_record_types: HashMap::new(),
+            wat_fields: None,
+            zstd_dictionary: None,
+            checkpoint_interval: None,
+        }
+    }
+
+    fn record(content_type: &str, content: &[u8]) -> Record {
+        let mut headers = HashMap::new();
+        headers.insert(String::from("WARC-Target-URI"), String::from("http://example.com/"));
+        headers.insert(String::from("Content-Type"), String::from(content_type));
+        Record {
+            headers,
+            content: content.to_vec(),
+            offset: 0,
+        }
+    }
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\n\r\nmissing";
+
+    #[test]
+    fn filters_responses_by_status_and_mime() {
+        let mut options = defaults();
+        options.statuses = Some(vec![200]);
+        let response = record("application/http; msgtype=response", RESPONSE);
+        assert!(warc_to_document(response, "response", &options).unwrap().is_none());
+
+        let mut options = defaults();
+        options.mimes = Some(vec![String::from("text/html")]);
+        let response = record("application/http; msgtype=response", RESPONSE);
+        assert!(warc_to_document(response, "response", &options).unwrap().is_none());
+    }
+
+    #[test]
+    fn scans_unparseable_responses_as_stored() {
+        let response = record("application/http; msgtype=response", b"<html>no status line</html>");
+        let document = warc_to_document(response, "response", &defaults()).unwrap().unwrap();
+        assert_eq!(document.data, b"<html>no status line</html>");
+        assert_eq!(document.mime.as_deref(), Some("application/http"));
+    }
+
+    #[test]
+    fn filters_only_responses() {
+        let mut options = defaults();
+        options.statuses = Some(vec![200]);
+        options.mimes = Some(vec![String::from("text/html")]);
+        let request = record("application/http; msgtype=request", b"GET / HTTP/1.1\r\n\r\n");
+        let document = warc_to_document(request, "request", &options).unwrap().unwrap();
+        assert_eq!(document.data, b"GET / HTTP/1.1\r\n\r\n");
+        let conversion = record("text/plain", b"some text");
+        assert!(warc_to_document(conversion, "conversion", &options).unwrap().is_some());
+        let metadata = record("application/warc-fields", b"fetchTimeMs: 12\r\n");
+        assert!(warc_to_document(metadata, "metadata", &options).unwrap().is_some());
+    }
+}
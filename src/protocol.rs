@@ -0,0 +1,140 @@
+//! What clients and the master must agree on to work together.
+//!
+//! Clients register by presenting the group's secret key in an
+//! `Authorization` header, and are issued an access key with an id. Every
+//! later request carries the key's id in `X-Key-Id`, the time in
+//! `X-Timestamp`, and in `X-Signature` an HMAC-SHA256 of its method, path,
+//! timestamp, and body, keyed with the access key, which itself is never
+//! sent again.
+
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The version of the protocol between clients and the master, which goes
+/// up whenever one side changes in a way the other must know about.
+/// Version 1 clients registered without describing themselves; version 2
+/// clients sent their capabilities, heartbeats, and completion reports;
+/// version 3 clients sign their requests.
+pub const PROTOCOL_VERSION: u64 = 3;
+
+/// The ieql release mieql is built against. ieql doesn't export its own
+/// version, so keep this in step with `Cargo.toml`.
+pub const IEQL_VERSION: &str = "0.3";
+
+/// The archive formats clients can read.
+pub const ARCHIVE_FORMATS: &[&str] = &["warc", "arc", "wet", "wat"];
+
+/// The compressions clients can read archives in.
+pub const COMPRESSIONS: &[&str] = &["none", "gzip", "zstd"];
+
+/// The characters percent-encoded in a source id: everything but the
+/// unreserved characters, so that `/`, `?`, and `#` in archive urls stay
+/// part of the id.
+const SOURCE_ID: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// How far a signed request's timestamp may be from the master's clock.
+/// Signatures are remembered this long to catch replays.
+pub const SIGNATURE_WINDOW: Duration = Duration::from_secs(300);
+
+/// The MAC of a request: its method, path, timestamp (in milliseconds
+/// since the epoch), and body, keyed with the client's access key.
+fn request_mac(key: &str, method: &str, path: &str, timestamp: u64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.input(format!("{}\n{}\n{}\n", method, path, timestamp).as_bytes());
+    mac.input(body);
+    mac
+}
+
+/// Signs a request, returning the signature in hex.
+pub fn sign(key: &str, method: &str, path: &str, timestamp: u64, body: &[u8]) -> String {
+    hex::encode(request_mac(key, method, path, timestamp, body).result().code())
+}
+
+/// Checks a request's signature, in constant time. Only lowercase hex, as
+/// `sign` writes it, is accepted, so that a request has just one valid
+/// signature for the master to remember against replays.
+pub fn verify(key: &str, method: &str, path: &str, timestamp: u64, body: &[u8], signature: &str) -> bool {
+    if signature.bytes().any(|x| !matches!(x, b'0'..=b'9' | b'a'..=b'f')) {
+        return false;
+    }
+    match hex::decode(signature) {
+        Ok(value) => request_mac(key, method, path, timestamp, body).verify(&value).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Encodes a source id as a single path segment, for `/heartbeat/` and
+/// `/complete_source/`.
+pub fn encode_source_id(id: &str) -> String {
+    utf8_percent_encode(id, SOURCE_ID).to_string()
+}
+
+/// Decodes a source id from the path segment `encode_source_id` made.
+pub fn decode_source_id(segment: &str) -> Option<String> {
+    match percent_decode_str(segment).decode_utf8() {
+        Ok(value) => Some(value.into_owned()),
+        Err(_) => None,
+    }
+}
+
+/// Compares a presented secret with the expected one, in constant time.
+pub fn secret_matches(presented: &str, secret: &str) -> bool {
+    presented.as_bytes().ct_eq(secret.as_bytes()).into()
+}
+
+/// The current time in milliseconds since the epoch, as requests are
+/// stamped.
+pub fn timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(value) => value.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_its_own_signatures() {
+        let signature = sign("key", "POST", "/mieql/output/", 1000, b"{}");
+        assert!(verify("key", "POST", "/mieql/output/", 1000, b"{}", signature.as_str()));
+    }
+
+    #[test]
+    fn rejects_altered_requests() {
+        let signature = sign("key", "POST", "/mieql/output/", 1000, b"{}");
+        assert!(!verify("other", "POST", "/mieql/output/", 1000, b"{}", signature.as_str()));
+        assert!(!verify("key", "GET", "/mieql/output/", 1000, b"{}", signature.as_str()));
+        assert!(!verify("key", "POST", "/mieql/source/", 1000, b"{}", signature.as_str()));
+        assert!(!verify("key", "POST", "/mieql/output/", 1001, b"{}", signature.as_str()));
+        assert!(!verify("key", "POST", "/mieql/output/", 1000, b"[]", signature.as_str()));
+    }
+
+    #[test]
+    fn rejects_signatures_in_another_case() {
+        let signature = sign("key", "GET", "/mieql/source/", 1000, b"");
+        assert!(signature.bytes().any(|x| x.is_ascii_alphabetic()));
+        let upper = signature.to_uppercase();
+        assert!(!verify("key", "GET", "/mieql/source/", 1000, b"", upper.as_str()));
+    }
+
+    #[test]
+    fn encodes_source_ids_as_one_segment() {
+        let id = "https://example.com/a b/é.warc.gz?x=1&y=2#z";
+        let segment = encode_source_id(id);
+        assert!(!segment.contains(['/', '?', '#', ' ']));
+        assert_eq!(decode_source_id(segment.as_str()), Some(String::from(id)));
+    }
+
+    #[test]
+    fn compares_secrets() {
+        assert!(secret_matches("secret", "secret"));
+        assert!(!secret_matches("secreT", "secret"));
+        assert!(!secret_matches("secre", "secret"));
+        assert!(!secret_matches("", "secret"));
+    }
+}
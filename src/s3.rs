@@ -0,0 +1,85 @@
+//! S3 client configuration. By default clients talk to `us-east-1` with
+//! the standard AWS credential chain, which is what Common Crawl needs;
+//! these options allow pointing at mirrors, MinIO, and other S3-compatible
+//! stores instead.
+//!
+//! Requests are always made with path-style addressing
+//! (`https://endpoint/bucket/key`), which S3-compatible stores accept.
+
+use rusoto_core::credential::{AwsCredentials, ProfileProvider, StaticProvider};
+use rusoto_core::region::Region;
+use rusoto_core::{DefaultCredentialsProvider, HttpClient};
+use rusoto_s3::S3Client;
+
+pub struct S3Options {
+    /// The AWS region name, e.g. `us-east-1`.
+    pub region: String,
+    /// A custom endpoint url, e.g. `http://localhost:9000` for MinIO.
+    pub endpoint: Option<String>,
+    /// A named profile from the AWS credentials file.
+    pub profile: Option<String>,
+    /// Send unsigned requests, as public buckets allow.
+    pub anonymous: bool,
+}
+
+impl Default for S3Options {
+    fn default() -> S3Options {
+        S3Options {
+            region: String::from("us-east-1"),
+            endpoint: None,
+            profile: None,
+            anonymous: false,
+        }
+    }
+}
+
+impl S3Options {
+    fn region(&self) -> Result<Region, String> {
+        match &self.endpoint {
+            Some(endpoint) => Ok(Region::Custom {
+                name: self.region.clone(),
+                endpoint: endpoint.clone(),
+            }),
+            None => match self.region.parse() {
+                Ok(value) => Ok(value),
+                Err(error) => Err(format!("invalid S3 region `{}`: `{}`", self.region, error)),
+            },
+        }
+    }
+
+    /// Builds an S3 client with these options.
+    pub fn client(&self) -> Result<S3Client, String> {
+        let region = self.region()?;
+        let dispatcher = match HttpClient::new() {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to create S3 http client: `{}`", error)),
+        };
+        if self.anonymous {
+            // Empty credentials are treated as anonymous, and such
+            // requests are sent unsigned.
+            let provider = StaticProvider::from(AwsCredentials::default());
+            return Ok(S3Client::new_with(dispatcher, provider, region));
+        }
+        match &self.profile {
+            Some(profile) => {
+                let mut provider = match ProfileProvider::new() {
+                    Ok(value) => value,
+                    Err(error) => {
+                        return Err(format!("unable to load AWS profiles: `{}`", error))
+                    }
+                };
+                provider.set_profile(profile.as_str());
+                Ok(S3Client::new_with(dispatcher, provider, region))
+            }
+            None => {
+                let provider = match DefaultCredentialsProvider::new() {
+                    Ok(value) => value,
+                    Err(error) => {
+                        return Err(format!("unable to load AWS credentials: `{}`", error))
+                    }
+                };
+                Ok(S3Client::new_with(dispatcher, provider, region))
+            }
+        }
+    }
+}
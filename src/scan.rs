@@ -0,0 +1,146 @@
+//! Local scans run queries against archives named on the command line,
+//! without a master, and write each output as a line of JSON. Inputs may
+//! be local paths or any location `source.rs` understands.
+
+use crate::pipeline::{self, Progress, ScanStatus};
+use crate::s3::S3Options;
+use crate::source;
+use ieql::output::output::OutputBatch;
+use ieql::query::query::Query;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+fn load_query(path: &str) -> Result<Query, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(value) => value,
+        Err(error) => return Err(format!("unable to read query `{}`: `{}`", path, error)),
+    };
+    let mut query: Query = match ron::de::from_str(text.as_str()) {
+        Ok(value) => value,
+        Err(error) => return Err(format!("unable to parse query `{}`: `{}`", path, error)),
+    };
+    // Outputs are attributed by query id, so fall back to the file name.
+    if query.id.is_none() {
+        query.id = Path::new(path)
+            .file_stem()
+            .map(|x| x.to_string_lossy().into_owned());
+    }
+    Ok(query)
+}
+
+fn write_outputs(writer: &mut dyn Write, output_batch: OutputBatch) -> Result<usize, String> {
+    for output in &output_batch.outputs {
+        let line = match serde_json::to_string(output) {
+            Ok(value) => value,
+            Err(error) => return Err(format!("unable to serialize output: `{}`", error)),
+        };
+        if let Err(error) = writeln!(writer, "{}", line) {
+            return Err(format!("unable to write output: `{}`", error));
+        }
+    }
+    match writer.flush() {
+        Ok(_) => Ok(output_batch.outputs.len()),
+        Err(error) => Err(format!("unable to write output: `{}`", error)),
+    }
+}
+
+pub fn main(
+    input_paths: Vec<String>,
+    query_paths: Vec<String>,
+    output_path: Option<String>,
+    threads: u8,
+    options: pipeline::Options,
+    s3_options: S3Options,
+) {
+    let mut queries: Vec<Query> = Vec::new();
+    for path in &query_paths {
+        match load_query(path) {
+            Ok(value) => queries.push(value),
+            Err(error) => {
+                error!("{}", error);
+                std::process::exit(101);
+            }
+        }
+    }
+    info!("successfully loaded {} queries", queries.len());
+
+    let compiled_query_groups = match pipeline::compile_queries(queries, &options) {
+        Ok(value) => value,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(101);
+        }
+    };
+    if compiled_query_groups.is_empty() {
+        error!("none of the queries compile; nothing to scan for");
+        std::process::exit(101);
+    }
+    let scan_engines = pipeline::start_scan_engines(compiled_query_groups, threads);
+
+    let mut writer: Box<dyn Write> = match &output_path {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(error) => {
+                error!("unable to create output file `{}`: `{}`", path, error);
+                std::process::exit(101);
+            }
+        },
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+
+    let s3_client = match s3_options.client() {
+        Ok(value) => value,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(101);
+        }
+    };
+    let mut total_outputs = 0;
+    let mut incomplete = 0;
+    for path in &input_paths {
+        // Unlike locations from a master, plain paths here are local files.
+        let location = if path == "-" || path.contains("://") {
+            path.clone()
+        } else {
+            format!("file://{}", path)
+        };
+        let archive = match source::resolve(location.as_str(), &s3_client).and_then(|x| x.open()) {
+            Ok(value) => value,
+            Err(error) => {
+                error!("unable to open archive: {}", error);
+                std::process::exit(101);
+            }
+        };
+        info!("scanning archive `{}`", path);
+        let report = pipeline::scan_archive(
+            archive,
+            location.as_str(),
+            0,
+            &scan_engines,
+            &options,
+            &Progress::default(),
+            &mut |output_batch| match write_outputs(writer.as_mut(), output_batch) {
+                Ok(num) => {
+                    total_outputs += num;
+                    Ok(())
+                }
+                Err(error) => {
+                    error!("{}", error);
+                    Err(error)
+                }
+            },
+        );
+        if report.status != ScanStatus::Complete {
+            incomplete += 1;
+        }
+    }
+    info!(
+        "finished scanning {} archive(s) with {} outputs",
+        input_paths.len(),
+        total_outputs
+    );
+    if incomplete > 0 {
+        warn!("{} archive(s) were not scanned completely", incomplete);
+    }
+}
@@ -0,0 +1,280 @@
+//! Archive sources. A location handed out by the master (or given on the
+//! command line) names where an archive lives; resolving it produces a
+//! `Source` that can open the archive as a blocking reader, from its
+//! first byte or, to resume a scan, from partway through.
+//!
+//! Supported locations are `s3://bucket/key`, `file:///path`,
+//! `http://...`/`https://...`, and `-` for standard input. For
+//! compatibility with older masters, a location without a scheme is
+//! treated as `bucket/key` on S3.
+//!
+//! Remote archives are fetched with retries: a request that fails for a
+//! transient reason is retried with exponential backoff and jitter, and a
+//! body stream that breaks partway through is reopened with a ranged
+//! request from the last byte read, so the reader sees one unbroken
+//! archive.
+
+use crate::error::{backoff, retry, Error, Policy};
+use itertools::Itertools;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectRequest, S3Client, S3};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::thread;
+
+/// How many times a request is attempted, and how many times in a row a
+/// stream is reopened without reading anything, before giving up.
+const MAX_ATTEMPTS: u32 = 6;
+
+/// Somewhere an archive can be read from.
+pub trait Source {
+    /// Opens the archive for reading from `offset` bytes in.
+    fn open_at(&self, offset: u64) -> Result<Box<dyn Read + Send>, Error>;
+
+    /// Opens the archive for reading from its first byte.
+    fn open(&self) -> Result<Box<dyn Read + Send>, Error> {
+        self.open_at(0)
+    }
+}
+
+/// Resolves `location` to the source it names. `s3_client` is used for
+/// S3 locations.
+pub fn resolve(location: &str, s3_client: &S3Client) -> Result<Box<dyn Source>, Error> {
+    if location == "-" {
+        return Ok(Box::new(StdinSource));
+    }
+    if let Some(path) = location.strip_prefix("file://") {
+        return Ok(Box::new(FileSource {
+            path: String::from(path),
+        }));
+    }
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Ok(Box::new(HttpSource {
+            url: String::from(location),
+        }));
+    }
+    let path = match location.strip_prefix("s3://") {
+        Some(value) => value,
+        None if location.contains("://") => {
+            return Err(Error::Parse(format!(
+                "unsupported archive location `{}`",
+                location
+            )))
+        }
+        None => location,
+    };
+    let paths: Vec<&str> = path.split('/').collect();
+    Ok(Box::new(S3Source {
+        client: s3_client.clone(),
+        bucket: String::from(*paths.first().unwrap_or(&"commoncrawl")),
+        key: paths.iter().skip(1).join("/"),
+    }))
+}
+
+/// Opens a stream from a given offset, for `Resuming` to reopen with.
+type Reopen = Box<dyn FnMut(u64) -> Result<Box<dyn Read + Send>, Error> + Send>;
+
+/// A remote stream that, when reading fails, reopens itself from the
+/// last byte read and carries on.
+struct Resuming {
+    description: String,
+    reader: Option<Box<dyn Read + Send>>,
+    reopen: Reopen,
+    offset: u64,
+    failures: u32,
+}
+
+impl Resuming {
+    fn new(description: String, reader: Box<dyn Read + Send>, offset: u64, reopen: Reopen) -> Resuming {
+        Resuming {
+            description,
+            reader: Some(reader),
+            reopen,
+            offset,
+            failures: 0,
+        }
+    }
+
+    /// Counts a failure, waiting before the next attempt, or gives up if
+    /// there have been too many in a row.
+    fn fail(&mut self, error: String) -> io::Result<()> {
+        self.failures += 1;
+        if self.failures >= MAX_ATTEMPTS {
+            return Err(io::Error::other(format!(
+                "{} (gave up after {} attempts)",
+                error, self.failures
+            )));
+        }
+        let delay = backoff(self.failures);
+        warn!(
+            "{}; reopening from byte {} in {:.1}s",
+            error,
+            self.offset,
+            delay.as_secs_f64()
+        );
+        thread::sleep(delay);
+        Ok(())
+    }
+}
+
+impl Read for Resuming {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let reader = match &mut self.reader {
+                Some(value) => value,
+                None => match (self.reopen)(self.offset) {
+                    Ok(value) => self.reader.get_or_insert(value),
+                    Err(error) if error.policy() == Policy::Retry => {
+                        self.fail(error.to_string())?;
+                        continue;
+                    }
+                    Err(error) => return Err(io::Error::other(error.to_string())),
+                },
+            };
+            match reader.read(buf) {
+                Ok(count) => {
+                    self.offset += count as u64;
+                    if count > 0 {
+                        self.failures = 0;
+                    }
+                    return Ok(count);
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => return Err(error),
+                Err(error) => {
+                    self.reader = None;
+                    let message = format!(
+                        "reading `{}` failed at byte {}: `{}`",
+                        self.description, self.offset, error
+                    );
+                    self.fail(message)?;
+                }
+            }
+        }
+    }
+}
+
+pub struct S3Source {
+    client: S3Client,
+    bucket: String,
+    key: String,
+}
+
+impl Source for S3Source {
+    fn open_at(&self, offset: u64) -> Result<Box<dyn Read + Send>, Error> {
+        let (client, bucket, key) = (self.client.clone(), self.bucket.clone(), self.key.clone());
+        let reader = retry(MAX_ATTEMPTS, || get_object(&client, bucket.as_str(), key.as_str(), offset))?;
+        let description = format!("s3://{}/{}", self.bucket, self.key);
+        Ok(Box::new(Resuming::new(
+            description,
+            reader,
+            offset,
+            Box::new(move |x| get_object(&client, bucket.as_str(), key.as_str(), x)),
+        )))
+    }
+}
+
+fn get_object(client: &S3Client, bucket: &str, key: &str, offset: u64) -> Result<Box<dyn Read + Send>, Error> {
+    let request = GetObjectRequest {
+        bucket: String::from(bucket),
+        key: String::from(key),
+        range: match offset {
+            0 => None,
+            _ => Some(format!("bytes={}-", offset)),
+        },
+        ..Default::default()
+    };
+    let result = match client.get_object(request).sync() {
+        Ok(value) => value,
+        Err(error) => {
+            let message = format!("unable to load object `s3://{}/{}`: `{}`", bucket, key, error);
+            // Throttling (`SlowDown`) and server errors arrive as unknown
+            // errors with their status.
+            return Err(match error {
+                RusotoError::HttpDispatch(_) => Error::Network(message),
+                RusotoError::Unknown(response)
+                    if response.status.is_server_error() || response.status.as_u16() == 429 =>
+                {
+                    Error::Network(message)
+                }
+                _ => Error::S3(message),
+            });
+        }
+    };
+    match result.body {
+        Some(value) => Ok(Box::new(value.into_blocking_read())),
+        None => Err(Error::Network(String::from("unable to get response body"))),
+    }
+}
+
+pub struct FileSource {
+    path: String,
+}
+
+impl Source for FileSource {
+    fn open_at(&self, offset: u64) -> Result<Box<dyn Read + Send>, Error> {
+        match File::open(&self.path).and_then(|mut x| x.seek(SeekFrom::Start(offset)).map(|_| x)) {
+            Ok(value) => Ok(Box::new(value)),
+            Err(error) => Err(Error::Decode(format!("unable to open `{}`: `{}`", self.path, error))),
+        }
+    }
+}
+
+pub struct HttpSource {
+    url: String,
+}
+
+impl Source for HttpSource {
+    fn open_at(&self, offset: u64) -> Result<Box<dyn Read + Send>, Error> {
+        let client = reqwest::Client::new();
+        let url = self.url.clone();
+        let reader = retry(MAX_ATTEMPTS, || http_get(&client, url.as_str(), offset))?;
+        Ok(Box::new(Resuming::new(
+            self.url.clone(),
+            reader,
+            offset,
+            Box::new(move |x| http_get(&client, url.as_str(), x)),
+        )))
+    }
+}
+
+fn http_get(client: &reqwest::Client, url: &str, offset: u64) -> Result<Box<dyn Read + Send>, Error> {
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header("Range", format!("bytes={}-", offset));
+    }
+    let mut response = match request.send() {
+        Ok(value) => value,
+        Err(error) => return Err(Error::Network(format!("unable to request `{}`: `{}`", url, error))),
+    };
+    let status = response.status();
+    if !status.is_success() {
+        let message = format!("unable to request `{}`: status {}", url, status);
+        if status.is_server_error() || status.as_u16() == 429 || status.as_u16() == 408 {
+            return Err(Error::Network(message));
+        }
+        return Err(Error::Decode(message));
+    }
+    // Servers that ignore the range send the whole archive instead.
+    if offset > 0 && status != reqwest::StatusCode::PARTIAL_CONTENT {
+        let skip = io::copy(&mut (&mut response).take(offset), &mut io::sink());
+        match skip {
+            Ok(count) if count == offset => (),
+            Ok(_) => return Err(Error::Decode(format!("`{}` ended before byte {}", url, offset))),
+            Err(error) => return Err(Error::Network(format!("unable to read `{}`: `{}`", url, error))),
+        }
+    }
+    Ok(Box::new(response))
+}
+
+pub struct StdinSource;
+
+impl Source for StdinSource {
+    fn open_at(&self, offset: u64) -> Result<Box<dyn Read + Send>, Error> {
+        match offset {
+            0 => Ok(Box::new(std::io::stdin())),
+            _ => Err(Error::Decode(String::from(
+                "standard input can't be resumed partway through",
+            ))),
+        }
+    }
+}
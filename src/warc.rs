@@ -0,0 +1,318 @@
+//! A streaming WARC record reader. Records are read header by header and
+//! their blocks by `Content-Length`, so block contents are never mistaken
+//! for record boundaries.
+
+use crate::compression;
+use std::collections::HashMap;
+use std::io::{BufRead, Read};
+
+/// A single WARC record.
+pub struct Record {
+    /// The named fields of the record header, as they appeared.
+    pub headers: HashMap<String, String>,
+    /// The record block, exactly `Content-Length` bytes long.
+    pub content: Vec<u8>,
+    /// The offset of the record's first byte in the (uncompressed) stream.
+    pub offset: u64,
+}
+
+impl Record {
+    /// Looks up a header field; WARC field names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Why a record could not be read.
+pub enum Failure {
+    /// The stream failed or ended early; nothing after it can be read.
+    Fatal(String),
+    /// The record was malformed; reading can resume at the next one.
+    Malformed(String),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::Fatal(error) | Failure::Malformed(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// An uncompressed archive stream, read line by line and block by block,
+/// that keeps count of its offset. The WARC and ARC readers share it.
+pub struct RecordStream<R: BufRead> {
+    reader: R,
+    offset: u64,
+}
+
+impl<R: BufRead> RecordStream<R> {
+    pub fn new(reader: R) -> RecordStream<R> {
+        RecordStream { reader, offset: 0 }
+    }
+
+    /// The offset of the next byte to be read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Reads one line, including its terminator. Returns an empty
+    /// buffer at the end of the stream.
+    pub fn read_line(&mut self) -> Result<Vec<u8>, Failure> {
+        let mut line = Vec::new();
+        // Bytes read before a failure are consumed all the same, so count
+        // them to keep offsets exact.
+        let result = self.reader.read_until(b'\n', &mut line);
+        self.offset += line.len() as u64;
+        match result {
+            Ok(_) => Ok(line),
+            Err(error) => Err(read_failure(error)),
+        }
+    }
+
+    /// Reads the `length`-byte block of the record at `record`.
+    pub fn read_block(&mut self, length: u64, record: u64) -> Result<Vec<u8>, Failure> {
+        let mut content = Vec::new();
+        let result = (&mut self.reader).take(length).read_to_end(&mut content);
+        self.offset += content.len() as u64;
+        if let Err(error) = result {
+            return Err(read_failure(error));
+        }
+        if (content.len() as u64) < length {
+            return Err(Failure::Fatal(format!(
+                "archive ended inside record at {} ({} of {} bytes)",
+                record,
+                content.len(),
+                length
+            )));
+        }
+        Ok(content)
+    }
+}
+
+/// Reads the records of one archive format.
+pub trait ReadRecord {
+    /// Reads the next record, or returns `None` at the end of the stream.
+    fn read_record(&mut self) -> Result<Option<Record>, Failure>;
+}
+
+/// Iterates over the records a reader reads. Malformed records are
+/// returned as errors and iteration goes on; I/O errors and truncation
+/// end it, since nothing after them can be trusted.
+pub struct Records<T: ReadRecord> {
+    reader: T,
+    finished: bool,
+}
+
+impl<T: ReadRecord> Records<T> {
+    pub fn new(reader: T) -> Records<T> {
+        Records {
+            reader,
+            finished: false,
+        }
+    }
+}
+
+impl<T: ReadRecord> Iterator for Records<T> {
+    type Item = Result<Record, Failure>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match self.reader.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(error) => {
+                self.finished = matches!(error, Failure::Fatal(_));
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Reads WARC records one at a time from an uncompressed stream. After a
+/// malformed record, reading resumes at the next line that begins one.
+pub struct WarcReader<R: BufRead> {
+    stream: RecordStream<R>,
+}
+
+impl<R: BufRead> WarcReader<R> {
+    pub fn new(reader: R) -> WarcReader<R> {
+        WarcReader {
+            stream: RecordStream::new(reader),
+        }
+    }
+}
+
+impl<R: BufRead> ReadRecord for WarcReader<R> {
+    fn read_record(&mut self) -> Result<Option<Record>, Failure> {
+        // Skip the blank lines that separate records, and anything else
+        // up to the next version line.
+        let mut skipped = 0;
+        let offset = loop {
+            let start = self.stream.offset();
+            let line = self.stream.read_line()?;
+            if line.is_empty() {
+                if skipped > 0 {
+                    return Err(Failure::Fatal(format!(
+                        "archive ended after {} bytes of unparseable data",
+                        skipped
+                    )));
+                }
+                return Ok(None);
+            }
+            if line.starts_with(b"WARC/") {
+                if skipped > 0 {
+                    warn!("skipped {} bytes before record at {}", skipped, start);
+                }
+                break start;
+            }
+            if !trim_line(&line).is_empty() {
+                skipped += line.len();
+            }
+        };
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut last_key: Option<String> = None;
+        loop {
+            let line = self.stream.read_line()?;
+            if line.is_empty() {
+                return Err(Failure::Fatal(format!(
+                    "archive ended inside the header of record at {}",
+                    offset
+                )));
+            }
+            let text = trim_line(&line);
+            if text.is_empty() {
+                break;
+            }
+            // Lines beginning with whitespace continue the previous field.
+            if line[0] == b' ' || line[0] == b'\t' {
+                if let Some(value) = last_key.as_ref().and_then(|x| headers.get_mut(x)) {
+                    value.push(' ');
+                    value.push_str(text.trim());
+                }
+                continue;
+            }
+            match text.find(':') {
+                Some(index) => {
+                    let key = String::from(text[..index].trim());
+                    let value = String::from(text[index + 1..].trim());
+                    headers.insert(key.clone(), value);
+                    last_key = Some(key);
+                }
+                None => {
+                    return Err(Failure::Malformed(format!(
+                        "malformed header line in record at {}",
+                        offset
+                    )))
+                }
+            }
+        }
+
+        let length: u64 = match headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, value)| value.parse())
+        {
+            Some(Ok(value)) => value,
+            Some(Err(_)) => {
+                return Err(Failure::Malformed(format!(
+                    "invalid Content-Length in record at {}",
+                    offset
+                )))
+            }
+            None => {
+                return Err(Failure::Malformed(format!(
+                    "missing Content-Length in record at {}",
+                    offset
+                )))
+            }
+        };
+        let content = self.stream.read_block(length, offset)?;
+
+        Ok(Some(Record {
+            headers,
+            content,
+            offset,
+        }))
+    }
+}
+
+fn trim_line(line: &[u8]) -> String {
+    String::from_utf8_lossy(line)
+        .trim_end_matches(['\r', '\n'])
+        .to_string()
+}
+
+/// Corrupt data that the decoder skipped past loses the record being
+/// read, but reading can go on; any other failure ends the archive.
+fn read_failure(error: std::io::Error) -> Failure {
+    if compression::is_resync(&error) {
+        Failure::Malformed(format!("lost record to corrupt data: {}", error))
+    } else {
+        Failure::Fatal(format!("unable to read archive: `{}`", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(content: &[u8]) -> Vec<u8> {
+        let mut data = format!(
+            "WARC/1.0\r\nWARC-Type: response\r\nWARC-Target-URI: http://example.com/\r\nContent-Length: {}\r\n\r\n",
+            content.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(content);
+        data.extend_from_slice(b"\r\n\r\n");
+        data
+    }
+
+    fn read(data: &[u8]) -> Vec<Result<Record, Failure>> {
+        Records::new(WarcReader::new(data)).collect()
+    }
+
+    #[test]
+    fn reads_blocks_by_length() {
+        let first = b"HTTP/1.1 200 OK\r\n\r\n<pre>\r\n\r\nWARC/1.0\r\nWARC-Type: response\r\n\r\n</pre>";
+        let data = [record(first), record(b"second")].concat();
+        let records = read(data.as_slice());
+        assert_eq!(records.len(), 2);
+        let first_record = records[0].as_ref().ok().unwrap();
+        assert_eq!(first_record.content, first.to_vec());
+        assert_eq!(first_record.offset, 0);
+        assert_eq!(first_record.header("warc-type"), Some("response"));
+        let second_record = records[1].as_ref().ok().unwrap();
+        assert_eq!(second_record.content, b"second");
+        assert_eq!(second_record.offset, record(first).len() as u64);
+    }
+
+    #[test]
+    fn resumes_after_malformed_records() {
+        let malformed = b"WARC/1.0\r\nWARC-Type: response\r\nnot a field\r\n\r\nbody\r\n\r\n";
+        let data = [malformed.to_vec(), record(b"next")].concat();
+        let records = read(data.as_slice());
+        assert_eq!(records.len(), 2);
+        assert!(matches!(records[0], Err(Failure::Malformed(_))));
+        assert_eq!(records[1].as_ref().ok().unwrap().content, b"next");
+    }
+
+    #[test]
+    fn ends_at_truncated_records() {
+        let mut data = [record(b"whole"), record(b"cut short")].concat();
+        data.truncate(data.len() - 8);
+        let records = read(data.as_slice());
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+        assert!(matches!(records[1], Err(Failure::Fatal(_))));
+    }
+}
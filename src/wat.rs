@@ -0,0 +1,203 @@
+//! WAT archives describe each capture with a JSON envelope in a `metadata`
+//! record: its WARC headers, HTTP headers, and for HTML pages the title,
+//! `<meta>` tags, and links. Documents are built from chosen fields of
+//! that envelope, so link- and header-level queries need not scan whole
+//! pages.
+
+use serde_json::Value;
+
+/// Short names for commonly scanned fields.
+const ALIASES: &[(&str, &str)] = &[
+    (
+        "links",
+        "Envelope.Payload-Metadata.HTTP-Response-Metadata.HTML-Metadata.Links",
+    ),
+    (
+        "title",
+        "Envelope.Payload-Metadata.HTTP-Response-Metadata.HTML-Metadata.Head.Title",
+    ),
+    (
+        "metas",
+        "Envelope.Payload-Metadata.HTTP-Response-Metadata.HTML-Metadata.Head.Metas",
+    ),
+    (
+        "headers",
+        "Envelope.Payload-Metadata.HTTP-Response-Metadata.Headers",
+    ),
+];
+
+/// Expands a field alias (e.g. `links`) to its full path, or returns the
+/// path as given.
+pub fn expand_field(field: &str) -> String {
+    match ALIASES.iter().find(|(alias, _)| field.eq_ignore_ascii_case(alias)) {
+        Some((_, path)) => String::from(*path),
+        None => String::from(field),
+    }
+}
+
+/// The JSON envelope of a WAT `metadata` record.
+pub struct Envelope {
+    json: Value,
+}
+
+impl Envelope {
+    pub fn parse(content: &[u8]) -> Result<Envelope, String> {
+        let json: Value = match serde_json::from_slice(content) {
+            Ok(value) => value,
+            Err(error) => return Err(format!("invalid WAT metadata: `{}`", error)),
+        };
+        if json.get("Envelope").is_none() {
+            return Err(String::from("WAT metadata has no envelope"));
+        }
+        Ok(Envelope { json })
+    }
+
+    fn warc_header(&self, name: &str) -> Option<&str> {
+        self.json["Envelope"]["WARC-Header-Metadata"][name].as_str()
+    }
+
+    /// Whether the envelope describes a `response` record, rather than a
+    /// request or the archive's own metadata.
+    pub fn is_response(&self) -> bool {
+        self.warc_header("WARC-Type") == Some("response")
+    }
+
+    pub fn target_uri(&self) -> Option<&str> {
+        self.warc_header("WARC-Target-URI")
+    }
+
+    fn response_metadata(&self) -> &Value {
+        &self.json["Envelope"]["Payload-Metadata"]["HTTP-Response-Metadata"]
+    }
+
+    /// The HTTP status of the described response.
+    pub fn status(&self) -> Option<u16> {
+        self.response_metadata()["Response-Message"]["Status"]
+            .as_str()
+            .and_then(|x| x.trim().parse().ok())
+    }
+
+    /// The media type of the described response, lowercased and without
+    /// parameters.
+    pub fn mime(&self) -> Option<String> {
+        let headers = self.response_metadata()["Headers"].as_object()?;
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+            .and_then(|(_, value)| value.as_str())
+            .and_then(|x| x.split(';').next())
+            .map(|x| x.trim().to_ascii_lowercase())
+            .filter(|x| !x.is_empty())
+    }
+
+    /// Collects the values at each dot-separated path, one per line.
+    /// Arrays along a path are searched element by element. Strings are
+    /// written as-is; objects (such as links) as compact JSON.
+    pub fn extract(&self, fields: &[String]) -> Vec<u8> {
+        let mut lines: Vec<String> = Vec::new();
+        for field in fields {
+            let path: Vec<&str> = field.split('.').filter(|x| !x.is_empty()).collect();
+            collect(&self.json, path.as_slice(), &mut lines);
+        }
+        lines.join("\n").into_bytes()
+    }
+}
+
+fn collect(value: &Value, path: &[&str], lines: &mut Vec<String>) {
+    match (value, path.split_first()) {
+        (Value::Array(items), _) => {
+            for item in items {
+                collect(item, path, lines);
+            }
+        }
+        (Value::Object(map), Some((key, rest))) => {
+            if let Some(child) = map.get(*key) {
+                collect(child, rest, lines);
+            }
+        }
+        (_, Some(_)) | (Value::Null, None) => (),
+        (Value::String(text), None) => lines.push(text.clone()),
+        (other, None) => lines.push(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn envelope() -> Envelope {
+        let json = json!({
+            "Envelope": {
+                "WARC-Header-Metadata": {
+                    "WARC-Type": "response",
+                    "WARC-Target-URI": "http://example.com/",
+                },
+                "Payload-Metadata": {
+                    "HTTP-Response-Metadata": {
+                        "Response-Message": { "Status": "200", "Reason": "OK" },
+                        "Headers": { "content-type": "Text/HTML; charset=UTF-8", "Content-Length": "1234" },
+                        "HTML-Metadata": {
+                            "Head": {
+                                "Title": "Example",
+                                "Metas": [
+                                    { "name": "description", "content": "An example" },
+                                    { "name": "robots", "content": null },
+                                ],
+                            },
+                            "Links": [
+                                { "path": "A@/href", "url": "/about" },
+                                { "path": "IMG@/src", "url": "/logo.png", "width": 10 },
+                            ],
+                        },
+                    },
+                },
+            },
+        });
+        Envelope::parse(json.to_string().as_bytes()).unwrap()
+    }
+
+    fn extract(envelope: &Envelope, fields: &[&str]) -> String {
+        let fields: Vec<String> = fields.iter().map(|x| expand_field(x)).collect();
+        String::from_utf8(envelope.extract(fields.as_slice())).unwrap()
+    }
+
+    #[test]
+    fn reads_the_described_response() {
+        let envelope = envelope();
+        assert!(envelope.is_response());
+        assert_eq!(envelope.target_uri(), Some("http://example.com/"));
+        assert_eq!(envelope.status(), Some(200));
+        assert_eq!(envelope.mime(), Some(String::from("text/html")));
+        assert!(Envelope::parse(b"{}").is_err());
+        assert!(Envelope::parse(b"not json").is_err());
+    }
+
+    #[test]
+    fn expands_aliases() {
+        assert_eq!(
+            expand_field("Links"),
+            "Envelope.Payload-Metadata.HTTP-Response-Metadata.HTML-Metadata.Links"
+        );
+        assert_eq!(expand_field("Envelope.Format"), "Envelope.Format");
+    }
+
+    #[test]
+    fn extracts_fields_through_arrays_and_objects() {
+        let envelope = envelope();
+        assert_eq!(extract(&envelope, &["title"]), "Example");
+        // Objects are written whole, and arrays are searched element by
+        // element, skipping nulls and missing keys.
+        assert_eq!(
+            extract(&envelope, &["links"]),
+            "{\"path\":\"A@/href\",\"url\":\"/about\"}\n{\"path\":\"IMG@/src\",\"url\":\"/logo.png\",\"width\":10}"
+        );
+        let links = expand_field("links");
+        let urls = format!("{}.url", links);
+        let widths = format!("{}.width", links);
+        assert_eq!(extract(&envelope, &[urls.as_str(), widths.as_str()]), "/about\n/logo.png\n10");
+        let contents = format!("{}.content", expand_field("metas"));
+        assert_eq!(extract(&envelope, &[contents.as_str()]), "An example");
+        assert_eq!(extract(&envelope, &["title.missing", "Envelope.Nothing"]), "");
+    }
+}